@@ -228,7 +228,26 @@ function initWebSocket(websocket_uri) {
     ws.addEventListener("message", (e) => {
       let message = JSON.parse(e.data);
       console.log(message);
-      state.uuid = message.uuid;
+      if (message.status === "shutting_down") {
+        console.warn(message.reason);
+        return;
+      }
+      if (message.status === "waitlisted" || message.status === "refused") {
+        console.warn(message.reason);
+        if (message.status === "refused") {
+          alert(message.reason);
+        }
+        return;
+      }
+      if (message.error) {
+        console.warn(
+          `Transcription of sequence ${message.failed_sequence_number} failed: ${message.error}`,
+        );
+        return;
+      }
+      if (message.uuid) {
+        state.uuid = message.uuid;
+      }
       if (!message.sequence_number) {
         // control message
         return;
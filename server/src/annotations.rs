@@ -0,0 +1,314 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::{self, readable_session, require_user, BadRequest, Forbidden, User};
+use crate::classes;
+use crate::error::{Er, E};
+use crate::session::SessionData;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Omission,
+    Mistranslation,
+    Register,
+    Grammar,
+    Other,
+}
+
+/// What part of a transcript an annotation is about.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum Anchor {
+    /// characters `start..end` of one segment of the transcript.
+    Text {
+        sequence_number: usize,
+        segment_number: usize,
+        start: usize,
+        end: usize,
+    },
+    /// milliseconds from the start of the session.
+    Time { start_ms: u64, end_ms: u64 },
+}
+
+/// A teacher's comment on part of a student's transcript.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Annotation {
+    pub id: Uuid,
+    pub session: Uuid,
+    pub author: Uuid,
+    pub category: Category,
+    pub comment: String,
+    #[serde(flatten)]
+    pub anchor: Anchor,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Annotation {
+    /// the annotated words, for text anchors.
+    pub fn excerpt(&self, session: &SessionData) -> Option<String> {
+        let Anchor::Text {
+            sequence_number,
+            segment_number,
+            start,
+            end,
+        } = self.anchor
+        else {
+            return None;
+        };
+        let translations = session.translations.lock().unwrap();
+        let segment = translations.segment(sequence_number, segment_number)?;
+        Some(
+            segment
+                .translation
+                .chars()
+                .skip(start)
+                .take(end.saturating_sub(start))
+                .collect(),
+        )
+    }
+}
+
+lazy_static! {
+    static ref ANNOTATIONS: RwLock<Vec<Annotation>> = RwLock::new(load_annotations());
+}
+
+fn annotations_file() -> String {
+    std::env::var("ANNOTATIONS_FILE").unwrap_or("annotations.json".to_string())
+}
+
+fn load_annotations() -> Vec<Annotation> {
+    match std::fs::read_to_string(annotations_file()) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            log::error!("Couldn't parse {}: {}", annotations_file(), e);
+            vec![]
+        }),
+        Err(_) => vec![],
+    }
+}
+
+fn save_annotations(annotations: &[Annotation]) -> E<()> {
+    std::fs::write(
+        annotations_file(),
+        serde_json::to_string_pretty(annotations)?,
+    )?;
+    Ok(())
+}
+
+/// the annotations of a session, in the order they were made.
+pub async fn annotations_of(session: &Uuid) -> Vec<Annotation> {
+    ANNOTATIONS
+        .read()
+        .await
+        .iter()
+        .filter(|x| x.session == *session)
+        .cloned()
+        .collect()
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewAnnotation {
+    category: Category,
+    comment: String,
+    #[serde(flatten)]
+    anchor: Anchor,
+}
+
+/// check that the anchor of a new annotation points into the transcript.
+fn validate(anchor: &Anchor, session: &SessionData) -> E<()> {
+    match *anchor {
+        Anchor::Text {
+            sequence_number,
+            segment_number,
+            start,
+            end,
+        } => {
+            let translations = session.translations.lock().unwrap();
+            let segment = translations
+                .segment(sequence_number, segment_number)
+                .ok_or(Er::new(format!(
+                    "no segment {} in sequence {}",
+                    segment_number, sequence_number
+                )))?;
+            if start >= end || end > segment.translation.chars().count() {
+                return Err(Er::new(format!("bad character range {}..{}", start, end)));
+            }
+        }
+        Anchor::Time { start_ms, end_ms } => {
+            if start_ms >= end_ms {
+                return Err(Er::new(format!("bad time range {}..{}", start_ms, end_ms)));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn add_annotation(new: NewAnnotation, session: &SessionData, author: &User) -> E<Annotation> {
+    validate(&new.anchor, session)?;
+    let annotation = Annotation {
+        id: Uuid::new_v4(),
+        session: session.uuid,
+        author: author.id,
+        category: new.category,
+        comment: new.comment,
+        anchor: new.anchor,
+        created_at: Utc::now(),
+    };
+    let mut annotations = ANNOTATIONS.write().await;
+    annotations.push(annotation.clone());
+    save_annotations(&annotations)?;
+    Ok(annotation)
+}
+
+/**
+ * the routes for annotations: GET and POST /annotations/<session uuid>, and
+ * DELETE /annotations/<session uuid>/<id>. Anyone who can see a session can
+ * read its annotations; teachers who can see it can add them.
+ */
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("annotations" / String))
+        .and(auth::user())
+        .and_then(|uuid: String, user: Option<User>| async move {
+            let (_, session) = readable_session(&uuid, &user).await?;
+            Ok::<_, Rejection>(warp::reply::json(&annotations_of(&session.uuid).await))
+        });
+
+    let create = warp::post()
+        .and(warp::path!("annotations" / String))
+        .and(require_user())
+        .and(warp::body::json())
+        .and_then(|uuid: String, user: User, new: NewAnnotation| async move {
+            let user_option = Some(user.clone());
+            let (_, session) = readable_session(&uuid, &user_option).await?;
+            let teaches_owner = match &session.owner {
+                Some(owner) => classes::teaches(&user.id, owner).await,
+                None => false,
+            };
+            if !user.is_teacher() && !teaches_owner {
+                return Err(warp::reject::custom(Forbidden));
+            }
+            let annotation = add_annotation(new, &session, &user)
+                .await
+                .map_err(|e| warp::reject::custom(BadRequest(e.to_string())))?;
+            Ok(warp::reply::json(&annotation))
+        });
+
+    let delete = warp::delete()
+        .and(warp::path!("annotations" / String / Uuid))
+        .and(require_user())
+        .and_then(|uuid: String, id: Uuid, user: User| async move {
+            let user_option = Some(user.clone());
+            let (_, session) = readable_session(&uuid, &user_option).await?;
+            let mut annotations = ANNOTATIONS.write().await;
+            let index = annotations
+                .iter()
+                .position(|x| x.id == id && x.session == session.uuid)
+                .ok_or(warp::reject::not_found())?;
+            if annotations[index].author != user.id && !user.is_admin() {
+                return Err(warp::reject::custom(Forbidden));
+            }
+            annotations.remove(index);
+            if let Err(e) = save_annotations(&annotations) {
+                log::error!("Couldn't save annotations: {}", e);
+                return Err(warp::reject());
+            }
+            Ok(warp::reply())
+        });
+
+    list.or(create).or(delete)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::session;
+    use crate::testing::{self, block_on};
+    use crate::translate::{TranslationResponse, TranslationResponses};
+    use std::sync::{Arc, Mutex};
+    use warp::http::StatusCode;
+
+    /// a session of `owner` whose transcript is one segment, "hello world".
+    fn session(owner: Option<&User>) -> SessionData {
+        let (session_id, _rx) = session::test_session("en");
+        let owner = owner.map(|x| x.id);
+        session::mutate_session_sync(&session_id, |session| session.owner = owner);
+        let mut session = session::get_session_sync(&session_id).unwrap();
+        let mut translations = TranslationResponses::new();
+        translations
+            .add_translation(&TranslationResponse {
+                sequence_number: 0,
+                translation: "hello world".to_string(),
+                num_segments: 1,
+                segment_number: 0,
+                segment_start: 0,
+                segment_end: 1000,
+                uuid: session.uuid.to_string(),
+                words: None,
+            })
+            .unwrap();
+        session.translations = Arc::new(Mutex::new(translations));
+        session
+    }
+
+    fn text(segment_number: usize, start: usize, end: usize) -> Anchor {
+        Anchor::Text {
+            sequence_number: 0,
+            segment_number,
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn text_anchors_stay_within_their_segment() {
+        let session = session(None);
+        assert!(validate(&text(0, 0, 5), &session).is_ok());
+        assert!(validate(&text(0, 6, 11), &session).is_ok());
+        assert!(validate(&text(0, 6, 12), &session).is_err());
+        assert!(validate(&text(0, 5, 5), &session).is_err());
+        assert!(validate(&text(1, 0, 5), &session).is_err());
+    }
+
+    #[test]
+    fn time_anchors_start_before_they_end() {
+        let session = session(None);
+        let time = |start_ms, end_ms| Anchor::Time { start_ms, end_ms };
+        assert!(validate(&time(0, 1000), &session).is_ok());
+        assert!(validate(&time(1000, 1000), &session).is_err());
+        assert!(validate(&time(2000, 1000), &session).is_err());
+    }
+
+    #[test]
+    fn only_authors_remove_their_annotations() {
+        let (author, author_token) = block_on(testing::user(Role::Teacher));
+        let (other, other_token) = block_on(testing::user(Role::Teacher));
+        let (student, _) = block_on(testing::user(Role::Student));
+        let session = session(Some(&student));
+        block_on(async {
+            let class = classes::create_class("class".to_string(), vec![author.id, other.id])
+                .await
+                .unwrap();
+            classes::join(&class.id, student.id, false).await.unwrap();
+            let new = NewAnnotation {
+                category: Category::Omission,
+                comment: "missing a word".to_string(),
+                anchor: text(0, 0, 5),
+            };
+            let annotation = add_annotation(new, &session, &author).await.unwrap();
+            let path = format!("/annotations/{}/{}", session.uuid, annotation.id);
+            let status = testing::status(&routes(), "DELETE", &path, &other_token, None).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            assert_eq!(annotations_of(&session.uuid).await.len(), 1);
+            let status = testing::status(&routes(), "DELETE", &path, &author_token, None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(annotations_of(&session.uuid).await.is_empty());
+        });
+    }
+}
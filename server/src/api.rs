@@ -1,75 +1,364 @@
 use askama::Template; // bring trait in scope
 
-use crate::session::{get_sessions, mark_session_for_closure_uuid, user_connected, SessionData};
-use crate::translate;
+use crate::annotations::Annotation;
+use crate::auth::{self, owned_session, readable_session, User};
+use crate::export::Format;
+use crate::limits::{self, Client, TooManyRequests};
+use crate::queue::{Priority, TranslationQueue};
+use crate::router::Router;
+use crate::session::{
+    cancel_session, get_sessions, mark_session_for_closure, user_connected, SessionData,
+    SessionOptions,
+};
+use crate::tls::{PeerAddr, TlsFiles};
 
-use crossbeam_channel::Sender;
+use chrono::{DateTime, Utc};
 use rust_embed::RustEmbed;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use uuid::Uuid;
+use warp::hyper::server::accept;
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::hyper::{Body, Request};
 use warp::reply::Json;
-use warp::Filter;
+use warp::{Filter, Reply};
 
 #[derive(Template)]
 #[template(path = "index.html", escape = "none")]
 pub struct Index {
     sessions: Vec<SessionData>,
+    user: Option<User>,
+    total: usize,
+    previous: Option<String>,
+    next: Option<String>,
 }
 
-pub async fn index() -> std::result::Result<impl warp::Reply, warp::Rejection> {
-    let mut sessions = get_sessions().await.ok_or(warp::reject::reject())?;
-    sessions.sort_by(|a, b| {
-        a.created_at
-            .partial_cmp(&b.created_at)
-            .expect("Unexpected error in comparison")
-    });
+const PER_PAGE: usize = 20;
+const MAX_PER_PAGE: usize = 100;
+
+/// Which sessions to list, and how.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SessionQuery {
+    /// 1-based.
+    page: Option<usize>,
+    per_page: Option<usize>,
+    /// the resource, i.e. the asset, the session was recorded for.
+    asset: Option<String>,
+    lang: Option<String>,
+    /// created at or after.
+    from: Option<DateTime<Utc>>,
+    /// created before.
+    to: Option<DateTime<Utc>>,
+    /// `finished` or `in_progress`.
+    status: Option<String>,
+    /// `created_at` (the default), `updated_at` or `language`.
+    sort: Option<String>,
+    /// `asc` or `desc` (the default).
+    order: Option<String>,
+    /// whose sessions to list, by default the caller's own. Teachers can
+    /// list those of their students.
+    user: Option<Uuid>,
+}
+
+impl SessionQuery {
+    fn page(&self) -> usize {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    fn per_page(&self) -> usize {
+        self.per_page.unwrap_or(PER_PAGE).clamp(1, MAX_PER_PAGE)
+    }
+
+    fn matches(&self, session: &SessionData) -> bool {
+        self.asset
+            .as_ref()
+            .is_none_or(|asset| session.resource.as_ref() == Some(asset))
+            && self
+                .lang
+                .as_ref()
+                .is_none_or(|lang| session.language == *lang)
+            && self.from.is_none_or(|from| session.created_at >= from)
+            && self.to.is_none_or(|to| session.created_at < to)
+            && match self.status.as_deref() {
+                Some("finished") => !session.valid,
+                Some("in_progress") => session.valid,
+                _ => true,
+            }
+    }
 
-    let template = Index { sessions };
+    fn sort(&self, sessions: &mut [SessionData]) {
+        match self.sort.as_deref() {
+            Some("updated_at") => sessions.sort_by_key(|x| x.updated_at),
+            Some("language") => sessions.sort_by(|a, b| a.language.cmp(&b.language)),
+            _ => sessions.sort_by_key(|x| x.created_at),
+        }
+        if self.order.as_deref() != Some("asc") {
+            sessions.reverse();
+        }
+    }
+
+    /// the link to another page of the same listing.
+    fn link(&self, page: usize) -> String {
+        let query = SessionQuery {
+            page: Some(page),
+            ..self.clone()
+        };
+        format!(
+            "/?{}",
+            serde_urlencoded::to_string(&query).unwrap_or_default()
+        )
+    }
+}
+
+/**
+ * the page of sessions asked for, and how many there are in all. Only
+ * sessions the user can see are listed: their own, or those of the user
+ * given in the query if it's one of their students. Anonymous sessions
+ * have no owner to list them for, so they are only reached by their uuid.
+ */
+pub async fn list_sessions(
+    user: &Option<User>,
+    query: &SessionQuery,
+) -> std::result::Result<(Vec<SessionData>, usize), warp::Rejection> {
+    let Some(owner) = query.user.or(user.as_ref().map(|user| user.id)) else {
+        return Ok((vec![], 0));
+    };
+    let mut sessions: Vec<SessionData> = vec![];
+    for session in get_sessions().await.ok_or(warp::reject::reject())? {
+        if session.owner == Some(owner)
+            && query.matches(&session)
+            && auth::can_read(user, &session).await
+        {
+            sessions.push(session);
+        }
+    }
+    query.sort(&mut sessions);
+    let total = sessions.len();
+    let sessions = sessions
+        .into_iter()
+        .skip((query.page() - 1) * query.per_page())
+        .take(query.per_page())
+        .collect();
+    Ok((sessions, total))
+}
+
+/// A page of sessions.
+#[derive(Serialize, ToSchema)]
+pub struct SessionList {
+    pub sessions: Vec<SessionData>,
+    pub page: usize,
+    pub per_page: usize,
+    /// how many sessions match in all.
+    pub total: usize,
+}
+
+/// A session with its place in the queue and the feedback on it.
+#[derive(Serialize, ToSchema)]
+pub struct SessionStatus {
+    #[serde(flatten)]
+    pub session: SessionData,
+    /// chunks waiting to be transcribed.
+    pub queue_depth: usize,
+    pub annotations: Vec<Annotation>,
+}
+
+async fn session_status(
+    queue: TranslationQueue,
+    uuid: String,
+    user: Option<User>,
+) -> std::result::Result<Json, warp::Rejection> {
+    let (session_id, session) = readable_session(&uuid, &user).await?;
+    let status = SessionStatus {
+        queue_depth: queue.depth(session_id),
+        annotations: crate::annotations::annotations_of(&session.uuid).await,
+        session,
+    };
+    Ok(warp::reply::json(&status))
+}
+
+/// the logged in user's sessions, or the login page if there is none.
+pub async fn index(
+    user: Option<User>,
+    query: SessionQuery,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    if user.is_none() && !auth::anonymous_allowed() {
+        return Ok(
+            warp::redirect::see_other(warp::http::Uri::from_static("/login.html")).into_response(),
+        );
+    }
+    let (sessions, total) = list_sessions(&user, &query).await?;
+    let page = query.page();
+    let previous = (page > 1).then(|| query.link(page - 1));
+    let next = (page * query.per_page() < total).then(|| query.link(page + 1));
+
+    let template = Index {
+        sessions,
+        user,
+        total,
+        previous,
+        next,
+    };
 
-    Ok(warp::reply::html(template.render().unwrap()))
+    Ok(warp::reply::html(template.render().unwrap()).into_response())
 }
 
-pub async fn serve(translate_tx: Sender<translate::TranslationRequest>) {
-    let chat = warp::path("chat")
-        .and(warp::query::<HashMap<String, String>>())
+/**
+ * all routes of the server, using the given queue and router for
+ * transcription, so that separate instances can be built with their own.
+ */
+pub fn routes(
+    queue: TranslationQueue,
+    router: Arc<Router>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let chat_queue = queue.clone();
+    let chat = warp::query::<HashMap<String, String>>()
         .and(warp::ws())
-        .map(move |params: HashMap<String, String>, ws: warp::ws::Ws| {
-            let tx = translate_tx.clone();
-            let lang: String = (params.get("lang").unwrap_or(&"de".to_string())).clone();
-            let resource: Option<String> = match params.get("resource") {
-                Some(s) => Some(s.clone()),
-                None => None,
+        .and(auth::session_user())
+        .and(limits::client_ip())
+        .and_then(
+            move |params: HashMap<String, String>,
+                  ws: warp::ws::Ws,
+                  user: Option<User>,
+                  ip: Option<IpAddr>| {
+                let client = Client {
+                    owner: user.map(|user| user.id),
+                    ip,
+                };
+                let queue = chat_queue.clone();
+                let lang: String = (params.get("lang").unwrap_or(&"de".to_string())).clone();
+                let resource: Option<String> = params.get("resource").cloned();
+                let priority = params
+                    .get("priority")
+                    .and_then(|x| Priority::parse(x))
+                    .unwrap_or(Priority::Practice);
+                let sample_rate: u32 = match params.get("rate") {
+                    Some(rate) => rate.to_string(),
+                    None => "44100".to_string(),
+                }
+                .parse()
+                .unwrap();
+                let options = SessionOptions {
+                    language: lang,
+                    priority,
+                    sample_rate,
+                    resource,
+                    model: params.get("model").cloned(),
+                };
+                async move {
+                    if !limits::has_quota(&client.quota_key()) {
+                        return Err(warp::reject::custom(TooManyRequests(
+                            "daily audio quota used up".to_string(),
+                        )));
+                    }
+                    if !limits::allow_connection(&client) {
+                        return Err(warp::reject::custom(TooManyRequests(
+                            "too many connections".to_string(),
+                        )));
+                    }
+                    Ok(ws
+                        .max_message_size(limits::max_message_bytes())
+                        .on_upgrade(move |socket| user_connected(socket, queue, options, client)))
+                }
+            },
+        );
+
+    let quota = warp::get()
+        .and(warp::path!("quota"))
+        .and(auth::user())
+        .and(limits::client_ip())
+        .map(|user: Option<User>, ip: Option<IpAddr>| {
+            let client = Client {
+                owner: user.map(|user| user.id),
+                ip,
             };
-            let sample_rate: u32 = match params.get("rate") {
-                Some(rate) => rate.to_string(),
-                None => "44100".to_string(),
+            warp::reply::json(&limits::quota(&client.quota_key()))
+        });
+
+    let close_queue = queue.clone();
+    let close = warp::post()
+        .and(warp::path!("close" / String))
+        .and(auth::user())
+        .and_then(move |uuid, user| {
+            let queue = close_queue.clone();
+            async move {
+                let (session_id, _) = owned_session(&uuid, &user).await?;
+                mark_session_for_closure(&queue, session_id).await;
+                Ok::<&str, warp::Rejection>("foo")
             }
-            .parse()
-            .unwrap();
-            ws.on_upgrade(move |socket| {
-                user_connected(socket, tx.clone(), lang, sample_rate, resource)
-            })
         });
 
-    let close = warp::post().and(warp::path!("close" / String).and_then(async move |uuid| {
-        mark_session_for_closure_uuid(uuid).await;
-        Ok::<&str, warp::Rejection>("foo")
-    }));
+    let abort_queue = queue.clone();
+    let abort = warp::post()
+        .and(warp::path!("abort" / String))
+        .and(auth::user())
+        .and_then(move |uuid, user| {
+            let queue = abort_queue.clone();
+            async move {
+                let (session_id, _) = owned_session(&uuid, &user).await?;
+                cancel_session(&queue, session_id).await;
+                Ok::<&str, warp::Rejection>("aborted")
+            }
+        });
 
-    let status = warp::path!("status" / String).and_then(async move |uuid| {
-        match crate::session::find_session_with_uuid(&uuid).await {
-            Some(session_id) => match crate::session::get_session(&session_id).await {
-                Some(session) => Ok::<Json, warp::Rejection>(warp::reply::json(&session)),
-                None => Err(warp::reject::not_found()),
-            },
-            None => Err(warp::reject::not_found()),
+    let metrics_queue = queue.clone();
+    let metrics = warp::get().and(warp::path!("metrics")).and_then(move || {
+        let queue = metrics_queue.clone();
+        async move {
+            match crate::metrics::render(&queue).await {
+                Ok(body) => Ok(warp::reply::with_header(
+                    body,
+                    "content-type",
+                    prometheus::TEXT_FORMAT,
+                )),
+                Err(e) => {
+                    log::error!("Error rendering metrics: {:?}", e);
+                    Err(warp::reject())
+                }
+            }
         }
     });
 
+    let healthz = warp::get()
+        .and(warp::path!("healthz"))
+        .map(crate::health::healthz);
+
+    let readyz_queue = queue.clone();
+    let readyz = warp::get()
+        .and(warp::path!("readyz"))
+        .map(move || crate::health::readyz(&readyz_queue, &router));
+
+    let queue_stats_queue = queue.clone();
+    let queue_stats = warp::get().and(warp::path!("queue")).map(move || {
+        warp::reply::json(&json!({
+            "queued": queue_stats_queue.queued(),
+            "estimated_latency": queue_stats_queue.estimated_latency().as_secs(),
+            "cancelled": queue_stats_queue.cancelled(),
+            "dead_letters": queue_stats_queue.dead_letters().len(),
+        }))
+    });
+
+    let status_queue = queue.clone();
+    let status = warp::path!("status" / String)
+        .and(auth::user())
+        .and_then(move |uuid, user| {
+            let queue = status_queue.clone();
+            async move { session_status(queue, uuid, user).await }
+        });
+
     let compare = warp::get()
         .and(warp::path!("compare" / String / String / String))
-        .and_then(async move |asset_id, uuid, lang| {
-            match crate::compare::compare(asset_id, uuid, lang).await {
+        .and(auth::user())
+        .and_then(async move |asset_id, uuid, lang, user| {
+            match crate::compare::compare(asset_id, uuid, lang, user).await {
                 Ok(x) => Ok(x),
                 Err(e) => {
                     log::error!("Error in compare: {:?}", e);
@@ -80,21 +369,99 @@ pub async fn serve(translate_tx: Sender<translate::TranslationRequest>) {
 
     let recordings_dir = std::env::var("RECORDINGS_DIR").unwrap_or("../clients/assets".to_string());
 
+    // recordings are served one at a time, as /recordings/<uuid>.wav, so
+    // that ownership can be checked.
     let recordings = warp::get()
-        .and(warp::path("recordings"))
-        .and(warp::fs::dir(recordings_dir));
-
-    let transcript = warp::path!("transcript" / String).and_then(async move |uuid| {
-        match crate::session::find_session_with_uuid(&uuid).await {
-            Some(session_id) => match crate::session::get_session(&session_id).await {
-                Some(session) => Ok(session.transcript().unwrap()),
-                None => Err(warp::reject::not_found()),
-            },
-            None => Err(warp::reject::not_found()),
-        }
-    });
+        .and(warp::path!("recordings" / String))
+        .and(auth::user())
+        .and_then(move |file: String, user| {
+            let recordings_dir = recordings_dir.clone();
+            async move {
+                let uuid = file.trim_end_matches(".wav").to_string();
+                let (_, session) = readable_session(&uuid, &user).await?;
+                match tokio::fs::read(format!(
+                    "{}/{}/{}.wav",
+                    recordings_dir, session.uuid, session.uuid
+                ))
+                .await
+                {
+                    Ok(wav) => Ok(warp::reply::with_header(wav, "content-type", "audio/wav")),
+                    Err(_) => Err(warp::reject::not_found()),
+                }
+            }
+        });
+
+    let transcript = warp::path!("transcript" / String)
+        .and(auth::user())
+        .and_then(async move |uuid, user| {
+            let (_, session) = readable_session(&uuid, &user).await?;
+            Ok::<String, warp::Rejection>(session.transcript().unwrap())
+        });
 
-    let index = warp::path::end().and_then(async move || crate::api::index().await);
+    // transcripts with their timing, as /export/<uuid>.<txt|srt|vtt|json>.
+    let export = warp::get()
+        .and(warp::path!("export" / String))
+        .and(auth::user())
+        .and_then(async move |file: String, user| {
+            let (uuid, format) = file
+                .rsplit_once('.')
+                .and_then(|(uuid, extension)| {
+                    Some((uuid.to_string(), Format::from_extension(extension)?))
+                })
+                .ok_or(warp::reject::not_found())?;
+            let (_, session) = readable_session(&uuid, &user).await?;
+            let body = crate::export::render(&crate::export::transcript(&session), format);
+            let reply = warp::reply::with_header(body, "content-type", format.content_type());
+            Ok::<_, warp::Rejection>(warp::reply::with_header(
+                reply,
+                "content-disposition",
+                format!(
+                    "attachment; filename=\"{}.{}\"",
+                    session.uuid,
+                    format.extension()
+                ),
+            ))
+        });
+
+    let dead_letters = warp::get()
+        .and(warp::path!("dead-letters"))
+        .and(auth::require_user())
+        .and_then(move |user: User| {
+            let queue = queue.clone();
+            async move {
+                if !user.is_admin() {
+                    return Err(warp::reject::custom(auth::Forbidden));
+                }
+                Ok(warp::reply::json(&queue.dead_letters()))
+            }
+        });
+
+    let index = warp::path::end()
+        .and(auth::user())
+        .and(warp::query::<SessionQuery>())
+        .and_then(async move |user, query| crate::api::index(user, query).await);
+
+    let sessions = warp::get()
+        .and(warp::path!("sessions"))
+        .and(auth::user())
+        .and(warp::query::<SessionQuery>())
+        .and_then(async move |user: Option<User>, query: SessionQuery| {
+            if user.is_none() && !auth::anonymous_allowed() {
+                return Err(warp::reject::custom(auth::Unauthorized));
+            }
+            let (sessions, total) = list_sessions(&user, &query).await?;
+            Ok(warp::reply::json(&SessionList {
+                sessions,
+                page: query.page(),
+                per_page: query.per_page(),
+                total,
+            }))
+        });
+
+    let openapi = warp::get()
+        .and(warp::path!("api" / "openapi.json"))
+        .map(|| warp::reply::json(&crate::openapi::ApiDoc::openapi()))
+        .with(cors());
 
     #[derive(RustEmbed)]
     #[folder = "../client"]
@@ -106,22 +473,279 @@ pub async fn serve(translate_tx: Sender<translate::TranslationRequest>) {
     struct Assets;
     let assets_serve = warp::path("assets").and(warp_embed::embed(&Assets));
 
-    let routes = index
+    // what other frontends and servers use, under /api/v1 and open to the
+    // origins in CORS_ALLOWED_ORIGINS. The bundled UI uses the same routes
+    // unversioned.
+    let api = auth::routes()
+        .or(crate::keys::routes())
+        .or(crate::classes::routes())
+        .or(crate::assignments::routes())
+        .or(crate::annotations::routes())
+        .or(crate::webhooks::routes())
+        .or(abort.clone())
+        .or(warp::path("chat")
+            .and(allowed_origin(allowed_origins()))
+            .and(chat.clone()))
+        .or(close.clone())
+        .or(export.clone())
+        .or(quota.clone())
+        .or(recordings.clone())
+        .or(sessions.clone())
+        .or(status.clone())
+        .or(transcript.clone());
+    let v1 = warp::path!("api" / "v1" / ..)
+        .and(api)
+        .recover(auth::handle_rejection)
+        .with(cors());
+
+    index
+        .or(v1)
+        .or(auth::routes())
+        .or(crate::keys::routes())
+        .or(crate::classes::routes())
+        .or(crate::assignments::routes())
+        .or(crate::annotations::routes())
+        .or(crate::webhooks::routes())
+        .or(crate::lti::routes())
+        .or(abort)
         .or(assets_serve)
-        .or(chat)
+        .or(warp::path("chat")
+            .and(allowed_origin(allowed_origins()))
+            .and(chat))
         .or(close)
         .or(compare)
+        .or(dead_letters)
+        .or(export)
+        .or(healthz)
+        .or(metrics)
+        .or(openapi)
+        .or(queue_stats)
+        .or(quota)
+        .or(readyz)
         .or(recordings)
+        .or(warp::path("api").and(sessions))
         .or(status)
         .or(static_content_serve)
-        .or(transcript);
+        .or(transcript)
+}
+
+/**
+ * the origins in `CORS_ALLOWED_ORIGINS`, a comma separated list such as
+ * `https://app.example.com`, or `None` for `*`, any origin.
+ */
+fn allowed_origins() -> Option<Vec<String>> {
+    let allowed = std::env::var("CORS_ALLOWED_ORIGINS").unwrap_or_default();
+    if allowed.trim() == "*" {
+        return None;
+    }
+    let origins = allowed
+        .split(',')
+        .map(|x| x.trim().trim_end_matches('/').to_string())
+        .filter(|x| !x.is_empty())
+        .filter(|x| match x.parse::<warp::http::Uri>() {
+            Ok(uri) if uri.scheme().is_some() && uri.host().is_some() => true,
+            _ => {
+                log::warn!("Ignoring bad origin {:?} in CORS_ALLOWED_ORIGINS", x);
+                false
+            }
+        })
+        .collect();
+    Some(origins)
+}
+
+/**
+ * CORS for /api/v1: the allowed origins' pages may call the API with the
+ * user's cookie, with `*` any page may call it with a token or API key.
+ * Unset, no other origin may use it.
+ */
+fn cors() -> warp::cors::Builder {
+    let cors = warp::cors()
+        .allow_methods(vec!["GET", "POST", "PUT", "DELETE"])
+        .allow_headers(vec!["authorization", "content-type", "x-api-key"])
+        .max_age(3600);
+    match allowed_origins() {
+        None => cors.allow_any_origin(),
+        Some(origins) => cors
+            .allow_credentials(true)
+            .allow_origins(origins.iter().map(|x| x.as_str())),
+    }
+}
+
+/**
+ * whether a page of `origin` may act with the user's cookie: the server's
+ * own pages, served from `host`, and those of the origins listed in
+ * `CORS_ALLOWED_ORIGINS`. With `*` no other origin may, as with CORS.
+ */
+pub fn may_use_cookie(origin: &str, host: Option<&str>) -> bool {
+    let origin = origin.trim_end_matches('/');
+    same_origin(origin, host)
+        || allowed_origins().is_some_and(|origins| origins.iter().any(|x| x == origin))
+}
+
+/// whether `origin` is the server's own, the `Host` its pages were served from.
+fn same_origin(origin: &str, host: Option<&str>) -> bool {
+    let authority = origin
+        .parse::<warp::http::Uri>()
+        .ok()
+        .and_then(|x| x.authority().map(|x| x.to_string()));
+    host.is_some() && authority.as_deref() == host
+}
+
+/**
+ * websockets aren't covered by CORS, so /chat and /api/v1/chat check the
+ * `Origin` browsers send against the same list, letting the server's own
+ * pages through too. Clients sending none aren't browsers, and are let
+ * through.
+ */
+fn allowed_origin(
+    origins: Option<Vec<String>>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and(warp::header::optional::<String>("host"))
+        .and_then(move |origin: Option<String>, host: Option<String>| {
+            let allowed = match (&origins, origin) {
+                (Some(origins), Some(origin)) => {
+                    let origin = origin.trim_end_matches('/');
+                    same_origin(origin, host.as_deref()) || origins.iter().any(|x| x == origin)
+                }
+                _ => true,
+            };
+            async move {
+                if !allowed {
+                    return Err(warp::reject::custom(auth::Forbidden));
+                }
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+/// serve until `signal` completes, then stop accepting connections.
+pub async fn serve(
+    queue: TranslationQueue,
+    router: Arc<Router>,
+    signal: impl Future<Output = ()> + Send + 'static,
+) {
+    let routes = routes(queue, router).recover(auth::handle_rejection);
     log::debug!("Starting server");
     let listen;
-    if let Ok(x) = std::env::var(" LISTEN") {
+    if let Ok(x) = std::env::var("LISTEN") {
         listen = x.parse().unwrap();
     } else {
         listen = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 3030);
     };
 
-    warp::serve(routes).run(listen).await;
+    match TlsFiles::from_env() {
+        Some(files) => {
+            let incoming = match crate::tls::incoming(listen, files).await {
+                Ok(incoming) => incoming,
+                Err(e) => {
+                    log::error!("Couldn't set up TLS: {}", e);
+                    return;
+                }
+            };
+            log::info!("Serving HTTPS on {}", listen);
+            let service = warp::service(routes);
+            let make_service = make_service_fn(move |stream: &TlsStream<TcpStream>| {
+                let peer = stream.get_ref().0.peer_addr().ok();
+                let mut service = service.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                        if let Some(peer) = peer {
+                            req.extensions_mut().insert(PeerAddr(peer));
+                        }
+                        service.call(req)
+                    }))
+                }
+            });
+            let server = warp::hyper::Server::builder(accept::from_stream(incoming))
+                .serve(make_service)
+                .with_graceful_shutdown(signal);
+            if let Err(e) = server.await {
+                log::error!("Server error: {}", e);
+            }
+        }
+        None => {
+            let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(listen, signal);
+            server.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, block_on};
+    use warp::filters::BoxedFilter;
+
+    /**
+     * start a session on `routes` and send it 15 seconds of silence at 1kHz,
+     * and then a little more as a chunk is only cut once more audio comes
+     * in. Returns the text of the first transcription.
+     */
+    async fn transcribe(routes: BoxedFilter<(Box<dyn warp::Reply>,)>) -> String {
+        let mut client = warp::test::ws()
+            .path("/chat?lang=de&rate=1000")
+            .handshake(routes)
+            .await
+            .unwrap();
+        let started: serde_json::Value =
+            serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert!(started["uuid"].is_string());
+        for samples in [15_300, 100] {
+            let audio: Vec<u8> = vec![0f32; samples]
+                .iter()
+                .flat_map(|x| x.to_le_bytes())
+                .collect();
+            client.send(warp::ws::Message::binary(audio)).await;
+        }
+        let transcription: serde_json::Value =
+            serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap();
+        transcription["translation"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn instances_have_their_own_queue_and_translator() {
+        let (first_queue, first) = testing::server(testing::Echo("first"));
+        let (second_queue, second) = testing::server(testing::Echo("second"));
+        block_on(async move {
+            assert_eq!(transcribe(first).await, "first");
+            assert_eq!(transcribe(second).await, "second");
+        });
+        assert_eq!(first_queue.queued() + second_queue.queued(), 0);
+    }
+
+    #[test]
+    fn anonymous_sessions_are_not_listed() {
+        let (_, _rx) = crate::session::test_session("de");
+        let (sessions, total) = block_on(list_sessions(&None, &SessionQuery::default())).unwrap();
+        assert!(sessions.is_empty());
+        assert_eq!(total, 0);
+    }
+
+    #[test]
+    fn websockets_are_only_opened_from_allowed_origins() {
+        let allowed = |origins: Option<Vec<String>>, origin: Option<&str>| {
+            let mut request = warp::test::request().header("host", "terplounge.example.com");
+            if let Some(origin) = origin {
+                request = request.header("origin", origin);
+            }
+            block_on(request.filter(&allowed_origin(origins))).is_ok()
+        };
+        let origins = Some(vec!["https://app.example.com".to_string()]);
+        assert!(allowed(origins.clone(), Some("https://app.example.com")));
+        assert!(allowed(origins.clone(), Some("https://app.example.com/")));
+        assert!(!allowed(origins.clone(), Some("https://evil.example.com")));
+        assert!(allowed(origins, None));
+        assert!(!allowed(Some(vec![]), Some("https://app.example.com")));
+        assert!(allowed(
+            Some(vec![]),
+            Some("https://terplounge.example.com")
+        ));
+        assert!(!allowed(
+            Some(vec![]),
+            Some("https://terplounge.example.com.evil.com")
+        ));
+        assert!(allowed(None, Some("https://evil.example.com")));
+    }
 }
@@ -0,0 +1,449 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::{self, require_user, BadRequest, Forbidden, PublicUser, User};
+use crate::classes;
+use crate::error::{Er, E};
+use crate::session::SessionData;
+
+/**
+ * Settings a teacher can fix for an assignment. They are kept with it for
+ * clients to read; the server doesn't enforce them.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct AssignmentSettings {
+    pub model: Option<String>,
+    /// seconds of audio per chunk sent for transcription.
+    pub chunk_seconds: Option<u32>,
+    /// how often the source may be replayed.
+    pub max_replays: Option<u32>,
+}
+
+/// An asset to interpret from one language into another by a deadline.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Assignment {
+    pub id: Uuid,
+    pub class_id: Uuid,
+    pub title: String,
+    pub asset_id: String,
+    pub source_lang: String,
+    pub target_lang: String,
+    #[serde(default)]
+    pub settings: AssignmentSettings,
+    pub due_at: DateTime<Utc>,
+    pub created_by: Uuid,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A student's session handed in for an assignment.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Submission {
+    pub id: Uuid,
+    pub assignment_id: Uuid,
+    pub student_id: Uuid,
+    pub session: Uuid,
+    pub submitted_at: DateTime<Utc>,
+    pub late: bool,
+    /// automatic score against the asset's text, see `compare::score`.
+    pub score: Option<f32>,
+}
+
+/// A submission with the student who handed it in, as teachers see it.
+#[derive(Serialize, ToSchema)]
+pub struct SubmissionInfo {
+    #[serde(flatten)]
+    pub submission: Submission,
+    pub student: Option<PublicUser>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Store {
+    assignments: Vec<Assignment>,
+    submissions: Vec<Submission>,
+}
+
+lazy_static! {
+    static ref STORE: RwLock<Store> = RwLock::new(load_store());
+}
+
+fn assignments_file() -> String {
+    std::env::var("ASSIGNMENTS_FILE").unwrap_or("assignments.json".to_string())
+}
+
+fn load_store() -> Store {
+    match std::fs::read_to_string(assignments_file()) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            log::error!("Couldn't parse {}: {}", assignments_file(), e);
+            Store::default()
+        }),
+        Err(_) => Store::default(),
+    }
+}
+
+fn save_store(store: &Store) -> E<()> {
+    std::fs::write(assignments_file(), serde_json::to_string_pretty(store)?)?;
+    Ok(())
+}
+
+pub async fn get_assignment(id: &Uuid) -> Option<Assignment> {
+    STORE
+        .read()
+        .await
+        .assignments
+        .iter()
+        .find(|x| x.id == *id)
+        .cloned()
+}
+
+/// the transcript of a session, from memory or, once it's gone, from disk.
+async fn transcript(uuid: &Uuid) -> Option<String> {
+    if let Some(session_id) = crate::session::find_session_with_uuid(&uuid.to_string()).await
+        && let Some(session) = crate::session::get_session(&session_id).await
+    {
+        return session.transcript().ok();
+    }
+    let dir = std::env::var("RECORDINGS_DIR").ok()?;
+    std::fs::read_to_string(format!("{}/{}/{}.txt", dir, uuid, uuid)).ok()
+}
+
+async fn score(assignment: &Assignment, session: &Uuid) -> Option<f32> {
+    let reference = crate::compare::reference(&assignment.asset_id, &assignment.target_lang)?;
+    let transcript = transcript(session).await?;
+    Some(crate::compare::score(&reference, &transcript))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewAssignment {
+    pub class_id: Uuid,
+    pub title: String,
+    pub asset_id: String,
+    pub source_lang: String,
+    pub target_lang: String,
+    #[serde(default)]
+    pub settings: AssignmentSettings,
+    pub due_at: DateTime<Utc>,
+}
+
+pub async fn create_assignment(new: NewAssignment, teacher: &User) -> E<Assignment> {
+    if crate::compare::reference(&new.asset_id, &new.target_lang).is_none() {
+        return Err(Er::new(format!(
+            "asset {} has no {} text",
+            new.asset_id, new.target_lang
+        )));
+    }
+    let assignment = Assignment {
+        id: Uuid::new_v4(),
+        class_id: new.class_id,
+        title: new.title,
+        asset_id: new.asset_id,
+        source_lang: new.source_lang,
+        target_lang: new.target_lang,
+        settings: new.settings,
+        due_at: new.due_at,
+        created_by: teacher.id,
+        created_at: Utc::now(),
+    };
+    let mut store = STORE.write().await;
+    store.assignments.push(assignment.clone());
+    save_store(&store)?;
+    Ok(assignment)
+}
+
+/**
+ * hand in a session, replacing the student's earlier submission if any.
+ * The session must be of the assignment's asset in its target language.
+ */
+async fn submit(assignment: &Assignment, student: &User, session: &SessionData) -> E<Submission> {
+    if session.resource.as_deref() != Some(assignment.asset_id.as_str())
+        || session.language != assignment.target_lang
+    {
+        return Err(Er::new(format!(
+            "session {} is not of asset {} in {}",
+            session.uuid, assignment.asset_id, assignment.target_lang
+        )));
+    }
+    let now = Utc::now();
+    let submission = Submission {
+        id: Uuid::new_v4(),
+        assignment_id: assignment.id,
+        student_id: student.id,
+        session: session.uuid,
+        submitted_at: now,
+        late: now > assignment.due_at,
+        score: score(assignment, &session.uuid).await,
+    };
+    let mut store = STORE.write().await;
+    store
+        .submissions
+        .retain(|x| !(x.assignment_id == assignment.id && x.student_id == student.id));
+    store.submissions.push(submission.clone());
+    save_store(&store)?;
+    crate::lti::submitted(&submission);
+    Ok(submission)
+}
+
+/**
+ * the submissions for an assignment. Scores are worked out again for
+ * sessions still in memory, as they may have been transcribing when they
+ * were handed in.
+ */
+async fn submissions(assignment: &Assignment) -> Vec<SubmissionInfo> {
+    let submissions: Vec<Submission> = STORE
+        .read()
+        .await
+        .submissions
+        .iter()
+        .filter(|x| x.assignment_id == assignment.id)
+        .cloned()
+        .collect();
+    let mut listing = vec![];
+    for mut submission in submissions {
+        if let Some(score) = score(assignment, &submission.session).await {
+            submission.score = Some(score);
+        }
+        let student = auth::get_user(&submission.student_id)
+            .await
+            .map(|x| x.public());
+        listing.push(SubmissionInfo {
+            submission,
+            student,
+        });
+    }
+    listing
+}
+
+/// whether the user teaches the class an assignment is for.
+async fn teaches(assignment: &Assignment, user: &User) -> bool {
+    match classes::get_class(&assignment.class_id).await {
+        Some(class) => user.is_admin() || class.teachers.contains(&user.id),
+        None => false,
+    }
+}
+
+async fn in_class(assignment: &Assignment, user: &User) -> bool {
+    match classes::get_class(&assignment.class_id).await {
+        Some(class) => class.students.contains(&user.id),
+        None => false,
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewSubmission {
+    session: Uuid,
+}
+
+/**
+ * the routes for assignments:
+ * GET/POST /assignments, GET /assignments/<id>,
+ * POST /assignments/<id>/submissions for students, and
+ * GET /assignments/<id>/submissions for teachers.
+ */
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("assignments"))
+        .and(require_user())
+        .and_then(|user: User| async move {
+            let classes: Vec<Uuid> = classes::classes_of(&user)
+                .await
+                .iter()
+                .map(|x| x.id)
+                .collect();
+            let assignments: Vec<Assignment> = STORE
+                .read()
+                .await
+                .assignments
+                .iter()
+                .filter(|x| classes.contains(&x.class_id))
+                .cloned()
+                .collect();
+            Ok::<_, Rejection>(warp::reply::json(&assignments))
+        });
+
+    let create = warp::post()
+        .and(warp::path!("assignments"))
+        .and(require_user())
+        .and(warp::body::json())
+        .and_then(|user: User, new: NewAssignment| async move {
+            match classes::get_class(&new.class_id).await {
+                Some(class) if user.is_admin() || class.teachers.contains(&user.id) => (),
+                Some(_) => return Err(warp::reject::custom(Forbidden)),
+                None => return Err(warp::reject::not_found()),
+            }
+            let assignment = create_assignment(new, &user)
+                .await
+                .map_err(|e| warp::reject::custom(BadRequest(e.to_string())))?;
+            Ok(warp::reply::json(&assignment))
+        });
+
+    let show = warp::get()
+        .and(warp::path!("assignments" / Uuid))
+        .and(require_user())
+        .and_then(|id: Uuid, user: User| async move {
+            let assignment = get_assignment(&id).await.ok_or(warp::reject::not_found())?;
+            if !teaches(&assignment, &user).await && !in_class(&assignment, &user).await {
+                return Err(warp::reject::not_found());
+            }
+            Ok(warp::reply::json(&assignment))
+        });
+
+    let hand_in = warp::post()
+        .and(warp::path!("assignments" / Uuid / "submissions"))
+        .and(require_user())
+        .and(warp::body::json())
+        .and_then(|id: Uuid, user: User, new: NewSubmission| async move {
+            let assignment = get_assignment(&id).await.ok_or(warp::reject::not_found())?;
+            if !in_class(&assignment, &user).await {
+                return Err(warp::reject::custom(Forbidden));
+            }
+            let user_option = Some(user.clone());
+            let (_, session) = auth::owned_session(&new.session.to_string(), &user_option).await?;
+            let submission = submit(&assignment, &user, &session)
+                .await
+                .map_err(|e| warp::reject::custom(BadRequest(e.to_string())))?;
+            Ok(warp::reply::json(&submission))
+        });
+
+    let list_submissions = warp::get()
+        .and(warp::path!("assignments" / Uuid / "submissions"))
+        .and(require_user())
+        .and_then(|id: Uuid, user: User| async move {
+            let assignment = get_assignment(&id).await.ok_or(warp::reject::not_found())?;
+            if !teaches(&assignment, &user).await {
+                return Err(warp::reject::custom(Forbidden));
+            }
+            Ok(warp::reply::json(&submissions(&assignment).await))
+        });
+
+    list.or(create).or(show).or(hand_in).or(list_submissions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::session;
+    use crate::testing::{self, block_on};
+    use chrono::Duration;
+    use warp::http::StatusCode;
+
+    /// an assignment of asset 1 into English for a class of `students`.
+    async fn assignment(teacher: &User, students: &[&User], due_in: Duration) -> Assignment {
+        let class = classes::create_class("class".to_string(), vec![teacher.id])
+            .await
+            .unwrap();
+        for student in students {
+            classes::join(&class.id, student.id, false).await.unwrap();
+        }
+        let new = NewAssignment {
+            class_id: class.id,
+            title: "assignment".to_string(),
+            asset_id: "1".to_string(),
+            source_lang: "de".to_string(),
+            target_lang: "en".to_string(),
+            settings: AssignmentSettings::default(),
+            due_at: Utc::now() + due_in,
+        };
+        create_assignment(new, teacher).await.unwrap()
+    }
+
+    /// a session `owner` recorded of `asset` in `language`.
+    fn session(owner: &User, asset: &str, language: &str) -> SessionData {
+        let (session_id, _rx) = session::test_session(language);
+        let (owner, asset) = (owner.id, asset.to_string());
+        session::mutate_session_sync(&session_id, |session| {
+            session.owner = Some(owner);
+            session.resource = Some(asset.clone());
+        });
+        session::get_session_sync(&session_id).unwrap()
+    }
+
+    /// hand in `session`, or list the submissions if there is none.
+    async fn request(method: &str, path: &str, token: &str, session: Option<Uuid>) -> StatusCode {
+        let body = session.map(|session| serde_json::json!({ "session": session }));
+        testing::status(&routes(), method, path, token, body).await
+    }
+
+    #[test]
+    fn only_sessions_of_the_asset_and_language_are_handed_in() {
+        let (teacher, _) = block_on(testing::user(Role::Teacher));
+        let (student, token) = block_on(testing::user(Role::Student));
+        let other_asset = session(&student, "2", "en");
+        let other_language = session(&student, "1", "de");
+        let right = session(&student, "1", "en");
+        block_on(async {
+            let assignment = assignment(&teacher, &[&student], Duration::days(1)).await;
+            let path = format!("/assignments/{}/submissions", assignment.id);
+            for wrong in [&other_asset, &other_language] {
+                let status = request("POST", &path, &token, Some(wrong.uuid)).await;
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+            }
+            assert!(submissions(&assignment).await.is_empty());
+            let status = request("POST", &path, &token, Some(right.uuid)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(submissions(&assignment).await.len(), 1);
+        });
+    }
+
+    #[test]
+    fn submissions_after_the_deadline_are_late() {
+        let (teacher, _) = block_on(testing::user(Role::Teacher));
+        let (student, _) = block_on(testing::user(Role::Student));
+        let session = session(&student, "1", "en");
+        block_on(async {
+            let due = assignment(&teacher, &[&student], Duration::days(1)).await;
+            let overdue = assignment(&teacher, &[&student], Duration::days(-1)).await;
+            assert!(!submit(&due, &student, &session).await.unwrap().late);
+            assert!(submit(&overdue, &student, &session).await.unwrap().late);
+        });
+    }
+
+    #[test]
+    fn handing_in_again_replaces_the_submission() {
+        let (teacher, _) = block_on(testing::user(Role::Teacher));
+        let (student, _) = block_on(testing::user(Role::Student));
+        let first = session(&student, "1", "en");
+        let second = session(&student, "1", "en");
+        block_on(async {
+            let assignment = assignment(&teacher, &[&student], Duration::days(1)).await;
+            submit(&assignment, &student, &first).await.unwrap();
+            submit(&assignment, &student, &second).await.unwrap();
+            let listing = submissions(&assignment).await;
+            assert_eq!(listing.len(), 1);
+            assert_eq!(listing[0].submission.session, second.uuid);
+        });
+    }
+
+    #[test]
+    fn students_do_not_see_each_others_submissions() {
+        let (teacher, teacher_token) = block_on(testing::user(Role::Teacher));
+        let (a, a_token) = block_on(testing::user(Role::Student));
+        let (b, b_token) = block_on(testing::user(Role::Student));
+        let b_session = session(&b, "1", "en");
+        block_on(async {
+            let assignment = assignment(&teacher, &[&a, &b], Duration::days(1)).await;
+            let path = format!("/assignments/{}/submissions", assignment.id);
+            let status = request("POST", &path, &b_token, Some(b_session.uuid)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                request("GET", &path, &a_token, None).await,
+                StatusCode::FORBIDDEN
+            );
+            // nor hand in B's session as their own; warp reports the not found
+            // session as whichever rejection of the other routes ranks higher.
+            let status = request("POST", &path, &a_token, Some(b_session.uuid)).await;
+            assert!(status.is_client_error());
+            assert_eq!(
+                request("GET", &path, &teacher_token, None).await,
+                StatusCode::OK
+            );
+            let listing = submissions(&assignment).await;
+            assert_eq!(listing.len(), 1);
+            assert_eq!(listing[0].submission.student_id, b.id);
+        });
+    }
+}
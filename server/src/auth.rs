@@ -0,0 +1,935 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::{OnceCell, RwLock};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::filters::BoxedFilter;
+use warp::http::{header, StatusCode};
+use warp::{Filter, Rejection, Reply};
+
+use crate::error::{Er, E};
+use crate::session::SessionData;
+
+const COOKIE: &str = "terplounge_token";
+const TOKEN_LIFETIME_DAYS: i64 = 30;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Teacher,
+    #[default]
+    Student,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct User {
+    pub id: Uuid,
+    pub username: String,
+    #[serde(default)]
+    pub role: Role,
+    /// argon2 hash, `None` for users who only log in with OIDC.
+    password_hash: Option<String>,
+    /// `issuer|subject` of the OIDC or LTI identity, if any.
+    oidc_subject: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl User {
+    fn new(username: String) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            username,
+            role: Role::default(),
+            password_hash: None,
+            oidc_subject: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// what the API shows of a user.
+    pub fn public(&self) -> PublicUser {
+        PublicUser {
+            id: self.id,
+            username: self.username.clone(),
+            role: self.role,
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// the user's subject at an OIDC or LTI issuer, if they came from there.
+    pub fn subject_at(&self, issuer: &str) -> Option<&str> {
+        self.oidc_subject
+            .as_ref()?
+            .strip_prefix(issuer)?
+            .strip_prefix('|')
+    }
+
+    /// teachers and admins can create classes and assignments.
+    pub fn is_teacher(&self) -> bool {
+        self.role == Role::Teacher || self.role == Role::Admin
+    }
+}
+
+/// A user as others see them.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct PublicUser {
+    pub id: Uuid,
+    pub username: String,
+    pub role: Role,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    user_id: Uuid,
+    expires_at: DateTime<Utc>,
+}
+
+/// state kept between redirecting to the OIDC issuer and its callback.
+#[derive(Clone, Debug)]
+struct PendingLogin {
+    nonce: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OidcDiscovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+lazy_static! {
+    static ref USERS: RwLock<HashMap<Uuid, User>> = RwLock::new(load_users());
+    static ref TOKENS: RwLock<HashMap<String, Token>> = RwLock::new(HashMap::new());
+    static ref PENDING_LOGINS: RwLock<HashMap<String, PendingLogin>> = RwLock::new(HashMap::new());
+    static ref OIDC_DISCOVERY: OnceCell<OidcDiscovery> = OnceCell::new();
+}
+
+#[derive(Debug)]
+pub struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Debug)]
+pub struct Forbidden;
+impl warp::reject::Reject for Forbidden {}
+
+#[derive(Debug)]
+pub struct BadRequest(pub String);
+impl warp::reject::Reject for BadRequest {}
+
+/// whether sessions may be used without logging in, from `ALLOW_ANONYMOUS`.
+pub fn anonymous_allowed() -> bool {
+    std::env::var("ALLOW_ANONYMOUS")
+        .map(|x| x == "true" || x == "1")
+        .unwrap_or(false)
+}
+
+fn users_file() -> String {
+    std::env::var("USERS_FILE").unwrap_or("users.json".to_string())
+}
+
+fn load_users() -> HashMap<Uuid, User> {
+    let users: Vec<User> = match std::fs::read_to_string(users_file()) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            log::error!("Couldn't parse {}: {}", users_file(), e);
+            vec![]
+        }),
+        Err(_) => vec![],
+    };
+    users.into_iter().map(|user| (user.id, user)).collect()
+}
+
+fn save_users(users: &HashMap<Uuid, User>) -> E<()> {
+    let users: Vec<&User> = users.values().collect();
+    std::fs::write(users_file(), serde_json::to_string_pretty(&users)?)?;
+    Ok(())
+}
+
+fn hash_password(password: &str) -> E<String> {
+    let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes())?;
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub async fn get_user(id: &Uuid) -> Option<User> {
+    USERS.read().await.get(id).cloned()
+}
+
+pub async fn find_user_by<F>(f: F) -> Option<User>
+where
+    F: Fn(&User) -> bool,
+{
+    USERS.read().await.values().find(|user| f(user)).cloned()
+}
+
+/// add a new user. The first user becomes admin, so someone can hand out roles.
+async fn add_user(mut user: User) -> E<User> {
+    let mut users = USERS.write().await;
+    if users.values().any(|x| x.username == user.username) {
+        return Err(Er::new(format!("user {} exists", user.username)));
+    }
+    if users.is_empty() {
+        user.role = Role::Admin;
+    }
+    users.insert(user.id, user.clone());
+    save_users(&users)?;
+    Ok(user)
+}
+
+pub async fn set_role(id: &Uuid, role: Role) -> E<User> {
+    let mut users = USERS.write().await;
+    let user = users
+        .get_mut(id)
+        .ok_or(Er::new(format!("no user {}", id)))?;
+    user.role = role;
+    let user = user.clone();
+    save_users(&users)?;
+    Ok(user)
+}
+
+/// usernames are shown in pages unescaped, so they are kept to a safe set.
+fn username_char(c: char) -> bool {
+    c.is_alphanumeric() || "._-@".contains(c)
+}
+
+pub async fn register(username: String, password: String) -> E<User> {
+    if username.trim().is_empty() || password.len() < 8 {
+        return Err(Er::new(
+            "username required and password must be at least 8 characters".to_string(),
+        ));
+    }
+    if username.trim().chars().any(|c| !username_char(c)) {
+        return Err(Er::new(
+            "usernames may only contain letters, digits and . _ - @".to_string(),
+        ));
+    }
+    let mut user = User::new(username.trim().to_string());
+    user.password_hash = Some(hash_password(&password)?);
+    add_user(user).await
+}
+
+pub async fn login(username: &str, password: &str) -> Option<User> {
+    let user = find_user_by(|user| user.username == username).await?;
+    if verify_password(password, user.password_hash.as_ref()?) {
+        Some(user)
+    } else {
+        None
+    }
+}
+
+async fn issue_token(user: &User) -> String {
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    TOKENS.write().await.insert(
+        token.clone(),
+        Token {
+            user_id: user.id,
+            expires_at: Utc::now() + Duration::days(TOKEN_LIFETIME_DAYS),
+        },
+    );
+    token
+}
+
+async fn user_for_token(token: &str) -> Option<User> {
+    let token = TOKENS.read().await.get(token).cloned()?;
+    if token.expires_at < Utc::now() {
+        return None;
+    }
+    get_user(&token.user_id).await
+}
+
+fn token_cookie(token: &str, max_age: i64) -> String {
+    format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+        COOKIE, token, max_age
+    )
+}
+
+/**
+ * log a user in and send them to `location`. `embedded` is for pages shown
+ * in another site's iframe, such as LTI launches, where browsers only keep
+ * `SameSite=None` cookies, and those only over HTTPS.
+ */
+pub async fn redirect_logged_in(
+    user: &User,
+    location: &str,
+    embedded: bool,
+) -> warp::reply::Response {
+    let token = issue_token(user).await;
+    let mut cookie = token_cookie(&token, TOKEN_LIFETIME_DAYS * 86400);
+    if embedded {
+        cookie = cookie.replace("SameSite=Lax", "SameSite=None; Secure");
+    }
+    let reply = warp::reply::with_header(StatusCode::FOUND, header::LOCATION, location);
+    warp::reply::with_header(reply, header::SET_COOKIE, cookie).into_response()
+}
+
+/**
+ * the login cookie, unless another site's page sent the request to change
+ * something. After an LTI launch the cookie is `SameSite=None`, so browsers
+ * send it with forms posted from anywhere; they also send the `Origin` of
+ * such requests, which has to be one that may use the cookie.
+ */
+fn cookie() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::cookie::optional::<String>(COOKIE)
+        .and(warp::method())
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("host"))
+        .map(
+            |cookie: Option<String>,
+             method: warp::http::Method,
+             origin: Option<String>,
+             host: Option<String>| match origin {
+                Some(origin)
+                    if !method.is_safe()
+                        && !crate::api::may_use_cookie(&origin, host.as_deref()) =>
+                {
+                    None
+                }
+                _ => cookie,
+            },
+        )
+}
+
+/**
+ * the token of a request: the cookie set at login, an `Authorization:
+ * Bearer` or `X-Api-Key` header, or, only when opening a websocket, whose
+ * clients can't set headers, a `token` query parameter. Elsewhere it would
+ * end up in logs and browser histories.
+ */
+fn token() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    cookie()
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(warp::header::optional::<String>("upgrade"))
+        .and(
+            warp::query::<HashMap<String, String>>()
+                .or(warp::any().map(HashMap::new))
+                .unify(),
+        )
+        .map(
+            |cookie: Option<String>,
+             authorization: Option<String>,
+             api_key: Option<String>,
+             upgrade: Option<String>,
+             query: HashMap<String, String>| {
+                let websocket = upgrade.is_some_and(|x| x.eq_ignore_ascii_case("websocket"));
+                authorization
+                    .and_then(|x| x.strip_prefix("Bearer ").map(|x| x.to_string()))
+                    .or(api_key)
+                    .or(cookie)
+                    .or(query.get("token").filter(|_| websocket).cloned())
+            },
+        )
+}
+
+/**
+ * the logged in user, or the user an API key is for, if any. Boxed, as
+ * nearly every route takes it and the futures of its filters nested in
+ * theirs otherwise overflow the stack of debug builds.
+ */
+pub fn user() -> BoxedFilter<(Option<User>,)> {
+    token()
+        .and_then(|token: Option<String>| async move {
+            Ok::<Option<User>, Rejection>(match token {
+                Some(token) if crate::keys::is_key(&token) => {
+                    crate::keys::user_for_key(&token).await
+                }
+                Some(token) => user_for_token(&token).await,
+                None => None,
+            })
+        })
+        .boxed()
+}
+
+/// the logged in user, rejecting the request if there is none.
+pub fn require_user() -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    user().and_then(
+        |user: Option<User>| async move { user.ok_or(warp::reject::custom(Unauthorized)) },
+    )
+}
+
+/// the user allowed to start a session: anyone if anonymous use is allowed.
+pub fn session_user() -> impl Filter<Extract = (Option<User>,), Error = Rejection> + Clone {
+    user().and_then(|user: Option<User>| async move {
+        if user.is_none() && !anonymous_allowed() {
+            return Err(warp::reject::custom(Unauthorized));
+        }
+        Ok(user)
+    })
+}
+
+/**
+ * whether a user may control a session, i.e. close or abort it. Anonymous
+ * sessions are controlled by whoever knows their uuid, which isn't listed
+ * anywhere.
+ */
+pub fn can_access(user: &Option<User>, session: &SessionData) -> bool {
+    may_access(user, session, anonymous_allowed())
+}
+
+/// `can_access`, given whether anonymous sessions are allowed.
+fn may_access(user: &Option<User>, session: &SessionData, anonymous: bool) -> bool {
+    match (&session.owner, user) {
+        (Some(owner), Some(user)) => *owner == user.id,
+        (Some(_), None) => false,
+        (None, _) => anonymous,
+    }
+}
+
+/**
+ * whether a user may see a session, its transcript and recording: those who
+ * can control it, admins, and the teachers of the owner's classes, whatever
+ * their own role, as LMS instructors only teach the classes of their courses.
+ */
+pub async fn can_read(user: &Option<User>, session: &SessionData) -> bool {
+    may_read(user, session, anonymous_allowed()).await
+}
+
+/// `can_read`, given whether anonymous sessions are allowed.
+async fn may_read(user: &Option<User>, session: &SessionData, anonymous: bool) -> bool {
+    if may_access(user, session, anonymous) {
+        return true;
+    }
+    match (user, &session.owner) {
+        (Some(user), Some(owner)) => {
+            user.is_admin() || crate::classes::teaches(&user.id, owner).await
+        }
+        _ => false,
+    }
+}
+
+async fn find_session(uuid: &String) -> std::result::Result<(usize, SessionData), Rejection> {
+    let session_id = crate::session::find_session_with_uuid(uuid)
+        .await
+        .ok_or(warp::reject::not_found())?;
+    let session = crate::session::get_session(&session_id)
+        .await
+        .ok_or(warp::reject::not_found())?;
+    Ok((session_id, session))
+}
+
+/**
+ * the session with the given uuid if the user may control it. Sessions of
+ * other users are reported as not found rather than forbidden, so as not to
+ * give away which uuids exist.
+ */
+pub async fn owned_session(
+    uuid: &String,
+    user: &Option<User>,
+) -> std::result::Result<(usize, SessionData), Rejection> {
+    let (session_id, session) = find_session(uuid).await?;
+    if !can_access(user, &session) {
+        return Err(warp::reject::not_found());
+    }
+    Ok((session_id, session))
+}
+
+/// the session with the given uuid if the user may see it.
+pub async fn readable_session(
+    uuid: &String,
+    user: &Option<User>,
+) -> std::result::Result<(usize, SessionData), Rejection> {
+    let (session_id, session) = find_session(uuid).await?;
+    if !can_read(user, &session).await {
+        return Err(warp::reject::not_found());
+    }
+    Ok((session_id, session))
+}
+
+struct OidcConfig {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+}
+
+/// the OIDC provider to log in with, if `OIDC_ISSUER` and the client settings are set.
+fn oidc_config() -> Option<OidcConfig> {
+    Some(OidcConfig {
+        issuer: std::env::var("OIDC_ISSUER").ok()?,
+        client_id: std::env::var("OIDC_CLIENT_ID").ok()?,
+        client_secret: std::env::var("OIDC_CLIENT_SECRET").ok()?,
+        redirect_url: std::env::var("OIDC_REDIRECT_URL").ok()?,
+    })
+}
+
+async fn oidc_discovery(config: &OidcConfig) -> Result<&'static OidcDiscovery, reqwest::Error> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        config.issuer.trim_end_matches('/')
+    );
+    OIDC_DISCOVERY
+        .get_or_try_init(|| async {
+            reqwest::get(url)
+                .await?
+                .error_for_status()?
+                .json::<OidcDiscovery>()
+                .await
+        })
+        .await
+}
+
+/// the URL to send the browser to for logging in with the OIDC issuer.
+async fn oidc_login_url(config: &OidcConfig) -> E<String> {
+    let discovery = oidc_discovery(config).await?;
+    let state = Uuid::new_v4().simple().to_string();
+    let nonce = Uuid::new_v4().simple().to_string();
+    {
+        let mut pending = PENDING_LOGINS.write().await;
+        pending.retain(|_, x| Utc::now() - x.created_at < Duration::minutes(10));
+        pending.insert(
+            state.clone(),
+            PendingLogin {
+                nonce: nonce.clone(),
+                created_at: Utc::now(),
+            },
+        );
+    }
+    let url = reqwest::Url::parse_with_params(
+        &discovery.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &config.client_id),
+            ("redirect_uri", &config.redirect_url),
+            ("scope", "openid profile email"),
+            ("state", &state),
+            ("nonce", &nonce),
+        ],
+    )?;
+    Ok(url.to_string())
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize, Debug)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+}
+
+/**
+ * finish an OIDC login: exchange the code for an ID token and find or
+ * create the user it identifies. The ID token comes straight from the
+ * token endpoint over TLS, so as the OIDC spec allows, its claims are
+ * checked but not its signature.
+ */
+async fn oidc_callback(config: &OidcConfig, code: &str, state: &str) -> E<User> {
+    let pending = PENDING_LOGINS
+        .write()
+        .await
+        .remove(state)
+        .ok_or(Er::new("unknown login state".to_string()))?;
+    let discovery = oidc_discovery(config).await?;
+    let client_id = &config.client_id;
+    let response = reqwest::Client::new()
+        .post(&discovery.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &config.redirect_url),
+            ("client_id", client_id),
+            ("client_secret", &config.client_secret),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<TokenResponse>()
+        .await?;
+
+    let payload = response
+        .id_token
+        .split('.')
+        .nth(1)
+        .ok_or(Er::new("malformed id token".to_string()))?;
+    let claims: IdTokenClaims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?;
+    let audience_ok = match &claims.aud {
+        Audience::One(aud) => aud == client_id,
+        Audience::Many(auds) => auds.contains(client_id),
+    };
+    if claims.iss != discovery.issuer
+        || !audience_ok
+        || claims.exp < Utc::now().timestamp()
+        || claims.nonce.as_ref() != Some(&pending.nonce)
+    {
+        return Err(Er::new("invalid id token".to_string()));
+    }
+
+    let subject = format!("{}|{}", claims.iss, claims.sub);
+    let name = claims
+        .preferred_username
+        .or(claims.email)
+        .unwrap_or(claims.sub);
+    external_user(subject, &name).await
+}
+
+/**
+ * the user with an identity from another system, `issuer|subject`, created
+ * with a free username based on `name` if it's new.
+ */
+pub async fn external_user(subject: String, name: &str) -> E<User> {
+    if let Some(user) = find_user_by(|user| user.oidc_subject.as_ref() == Some(&subject)).await {
+        return Ok(user);
+    }
+    let name: String = name.chars().filter(|c| username_char(*c)).collect();
+    let name = if name.is_empty() {
+        "user".to_string()
+    } else {
+        name
+    };
+    let mut username = name.clone();
+    let mut n = 1;
+    while find_user_by(|user| user.username == username)
+        .await
+        .is_some()
+    {
+        n += 1;
+        username = format!("{}{}", name, n);
+    }
+    let mut user = User::new(username);
+    user.oidc_subject = Some(subject);
+    add_user(user).await
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RoleChange {
+    role: Role,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+/// The reply to registering or logging in.
+#[derive(Serialize, ToSchema)]
+pub struct LoggedIn {
+    pub user: PublicUser,
+    /// to send as `Authorization: Bearer <token>` instead of the cookie.
+    pub token: String,
+}
+
+async fn logged_in(user: User) -> warp::reply::Response {
+    let token = issue_token(&user).await;
+    let body = LoggedIn {
+        user: user.public(),
+        token: token.clone(),
+    };
+    let cookie = token_cookie(&token, TOKEN_LIFETIME_DAYS * 86400);
+    warp::reply::with_header(warp::reply::json(&body), header::SET_COOKIE, cookie).into_response()
+}
+
+/// the routes for registering, logging in and out.
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let register = warp::post()
+        .and(warp::path!("register"))
+        .and(warp::body::json())
+        .and_then(|credentials: Credentials| async move {
+            let user = register(credentials.username, credentials.password)
+                .await
+                .map_err(|e| warp::reject::custom(BadRequest(e.to_string())))?;
+            Ok::<_, Rejection>(logged_in(user).await)
+        });
+
+    let login = warp::post()
+        .and(warp::path!("login"))
+        .and(warp::body::json())
+        .and_then(|credentials: Credentials| async move {
+            match login(&credentials.username, &credentials.password).await {
+                Some(user) => Ok(logged_in(user).await),
+                None => Err(warp::reject::custom(Unauthorized)),
+            }
+        });
+
+    let logout = warp::post()
+        .and(warp::path!("logout"))
+        .and(token())
+        .and_then(|token: Option<String>| async move {
+            if let Some(token) = token {
+                TOKENS.write().await.remove(&token);
+            }
+            Ok::<_, Rejection>(warp::reply::with_header(
+                warp::reply(),
+                header::SET_COOKIE,
+                token_cookie("", 0),
+            ))
+        });
+
+    let me = warp::get()
+        .and(warp::path!("me"))
+        .and(require_user())
+        .map(|user: User| warp::reply::json(&user.public()));
+
+    let users = warp::get()
+        .and(warp::path!("users"))
+        .and(require_user())
+        .and_then(|user: User| async move {
+            if !user.is_teacher() {
+                return Err(warp::reject::custom(Forbidden));
+            }
+            let users: Vec<PublicUser> = USERS.read().await.values().map(|x| x.public()).collect();
+            Ok(warp::reply::json(&users))
+        });
+
+    let set_role = warp::put()
+        .and(warp::path!("users" / Uuid / "role"))
+        .and(require_user())
+        .and(warp::body::json())
+        .and_then(|id: Uuid, user: User, role: RoleChange| async move {
+            if !user.is_admin() {
+                return Err(warp::reject::custom(Forbidden));
+            }
+            let changed = set_role(&id, role.role)
+                .await
+                .map_err(|_| warp::reject::not_found())?;
+            Ok(warp::reply::json(&changed.public()))
+        });
+
+    let oidc_login = warp::get()
+        .and(warp::path!("oidc" / "login"))
+        .and_then(|| async move {
+            let Some(config) = oidc_config() else {
+                return Err(warp::reject::not_found());
+            };
+            match oidc_login_url(&config).await {
+                Ok(url) => Ok(warp::reply::with_header(
+                    StatusCode::FOUND,
+                    header::LOCATION,
+                    url,
+                )),
+                Err(e) => {
+                    log::error!("Couldn't start OIDC login: {}", e);
+                    Err(warp::reject())
+                }
+            }
+        });
+
+    let oidc_callback = warp::get()
+        .and(warp::path!("oidc" / "callback"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|params: HashMap<String, String>| async move {
+            let (Some(code), Some(state)) = (params.get("code"), params.get("state")) else {
+                return Err(warp::reject::custom(BadRequest(
+                    "code and state required".to_string(),
+                )));
+            };
+            let Some(config) = oidc_config() else {
+                return Err(warp::reject::not_found());
+            };
+            let user = match oidc_callback(&config, code, state).await {
+                Ok(user) => user,
+                Err(e) => {
+                    log::warn!("OIDC login failed: {}", e);
+                    return Err(warp::reject::custom(Unauthorized));
+                }
+            };
+            Ok(redirect_logged_in(&user, "/", false).await)
+        });
+
+    register
+        .or(login)
+        .or(logout)
+        .or(me)
+        .or(users)
+        .or(set_role)
+        .or(oidc_login)
+        .or(oidc_callback)
+}
+
+/// How errors are reported.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorReply {
+    pub error: String,
+}
+
+/// turn our rejections into JSON errors with the right status.
+pub async fn handle_rejection(
+    rejection: Rejection,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let (status, message) = if rejection.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "not logged in".to_string())
+    } else if rejection.find::<Forbidden>().is_some() {
+        (StatusCode::FORBIDDEN, "not allowed".to_string())
+    } else if let Some(crate::limits::TooManyRequests(message)) =
+        rejection.find::<crate::limits::TooManyRequests>()
+    {
+        (StatusCode::TOO_MANY_REQUESTS, message.clone())
+    } else if let Some(BadRequest(message)) = rejection.find::<BadRequest>() {
+        (StatusCode::BAD_REQUEST, message.clone())
+    } else {
+        return Err(rejection);
+    };
+    Ok(
+        warp::reply::with_status(warp::reply::json(&ErrorReply { error: message }), status)
+            .into_response(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session;
+    use crate::testing::block_on;
+
+    fn user(role: Role) -> User {
+        User {
+            role,
+            ..User::new("user".to_string())
+        }
+    }
+
+    /// a session started by `owner`, `None` for an anonymous one.
+    fn session(owner: Option<&User>) -> SessionData {
+        let (session_id, _rx) = session::test_session("de");
+        let owner = owner.map(|x| x.id);
+        session::mutate_session_sync(&session_id, |session| session.owner = owner);
+        session::get_session_sync(&session_id).unwrap()
+    }
+
+    #[test]
+    fn only_owners_control_their_sessions() {
+        let owner = user(Role::Student);
+        let session = session(Some(&owner));
+        assert!(may_access(&Some(owner), &session, true));
+        assert!(!may_access(&Some(user(Role::Student)), &session, true));
+        assert!(!may_access(&Some(user(Role::Admin)), &session, true));
+        assert!(!may_access(&None, &session, true));
+    }
+
+    #[test]
+    fn anonymous_sessions_are_only_open_when_allowed() {
+        let session = session(None);
+        assert!(may_access(&None, &session, true));
+        assert!(may_access(&Some(user(Role::Student)), &session, true));
+        assert!(!may_access(&None, &session, false));
+        assert!(!may_access(&Some(user(Role::Admin)), &session, false));
+        assert!(!block_on(may_read(
+            &Some(user(Role::Admin)),
+            &session,
+            false
+        )));
+    }
+
+    #[test]
+    fn admins_and_teachers_of_the_owner_read_sessions() {
+        let owner = user(Role::Student);
+        let teacher = user(Role::Student);
+        let other_teacher = user(Role::Teacher);
+        let session = session(Some(&owner));
+        block_on(async {
+            let class = crate::classes::create_class("class".to_string(), vec![teacher.id])
+                .await
+                .unwrap();
+            crate::classes::join(&class.id, owner.id, false)
+                .await
+                .unwrap();
+            crate::classes::create_class("other".to_string(), vec![other_teacher.id])
+                .await
+                .unwrap();
+        });
+        let reads = |user: User| block_on(may_read(&Some(user), &session, false));
+        assert!(reads(owner.clone()));
+        assert!(reads(user(Role::Admin)));
+        assert!(reads(teacher));
+        assert!(!reads(other_teacher));
+        assert!(!reads(user(Role::Student)));
+        assert!(!block_on(may_read(&None, &session, true)));
+    }
+
+    #[test]
+    fn sessions_of_others_are_not_found() {
+        let owner = user(Role::Student);
+        let uuid = session(Some(&owner)).uuid.to_string();
+        let admin = Some(user(Role::Admin));
+        block_on(async {
+            assert!(owned_session(&uuid, &Some(owner)).await.is_ok());
+            let other = owned_session(&uuid, &Some(user(Role::Student))).await;
+            assert!(other.unwrap_err().is_not_found());
+            assert!(owned_session(&uuid, &admin).await.is_err());
+            assert!(readable_session(&uuid, &admin).await.is_ok());
+            assert!(owned_session(&Uuid::new_v4().to_string(), &admin)
+                .await
+                .unwrap_err()
+                .is_not_found());
+        });
+    }
+
+    #[test]
+    fn query_tokens_only_open_websockets() {
+        let token = |upgrade: Option<&str>| {
+            let mut request = warp::test::request().path("/chat?token=secret");
+            if let Some(upgrade) = upgrade {
+                request = request.header("upgrade", upgrade);
+            }
+            block_on(request.filter(&token())).unwrap()
+        };
+        assert_eq!(token(Some("websocket")), Some("secret".to_string()));
+        assert_eq!(token(Some("WebSocket")), Some("secret".to_string()));
+        assert_eq!(token(None), None);
+        assert_eq!(token(Some("h2c")), None);
+    }
+
+    #[test]
+    fn other_sites_change_nothing_with_the_cookie() {
+        let token = |method: &str, origin: Option<&str>, bearer: bool| {
+            let mut request = warp::test::request()
+                .method(method)
+                .path("/sessions")
+                .header("host", "terplounge.example.com");
+            request = match bearer {
+                true => request.header("authorization", "Bearer secret"),
+                false => request.header("cookie", format!("{}=secret", COOKIE)),
+            };
+            if let Some(origin) = origin {
+                request = request.header("origin", origin);
+            }
+            block_on(request.filter(&token())).unwrap()
+        };
+        let secret = Some("secret".to_string());
+        let own = Some("https://terplounge.example.com");
+        let other = Some("https://evil.example.com");
+        assert_eq!(token("POST", own, false), secret);
+        assert_eq!(token("DELETE", own, false), secret);
+        assert_eq!(token("POST", None, false), secret);
+        assert_eq!(token("GET", other, false), secret);
+        assert_eq!(token("POST", other, false), None);
+        assert_eq!(token("DELETE", other, false), None);
+        assert_eq!(token("POST", Some("null"), false), None);
+        assert_eq!(token("POST", other, true), secret);
+    }
+}
@@ -0,0 +1,422 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::{self, require_user, BadRequest, Forbidden, PublicUser, User};
+use crate::error::{Er, E};
+
+/// A group of students and the teachers who can review their work.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Class {
+    pub id: Uuid,
+    pub name: String,
+    pub teachers: Vec<Uuid>,
+    pub students: Vec<Uuid>,
+    /// users asked to join as students who haven't accepted yet. Teachers
+    /// only see the sessions of students who have.
+    #[serde(default)]
+    pub invited: Vec<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Class {
+    fn is_teacher(&self, user: &User) -> bool {
+        user.is_admin() || self.teachers.contains(&user.id)
+    }
+
+    fn is_member(&self, user: &User) -> bool {
+        self.is_teacher(user) || self.students.contains(&user.id)
+    }
+
+    fn is_invited(&self, user: &User) -> bool {
+        self.invited.contains(&user.id)
+    }
+}
+
+lazy_static! {
+    static ref CLASSES: RwLock<HashMap<Uuid, Class>> = RwLock::new(load_classes());
+}
+
+fn classes_file() -> String {
+    std::env::var("CLASSES_FILE").unwrap_or("classes.json".to_string())
+}
+
+fn load_classes() -> HashMap<Uuid, Class> {
+    let classes: Vec<Class> = match std::fs::read_to_string(classes_file()) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            log::error!("Couldn't parse {}: {}", classes_file(), e);
+            vec![]
+        }),
+        Err(_) => vec![],
+    };
+    classes.into_iter().map(|class| (class.id, class)).collect()
+}
+
+fn save_classes(classes: &HashMap<Uuid, Class>) -> E<()> {
+    let classes: Vec<&Class> = classes.values().collect();
+    std::fs::write(classes_file(), serde_json::to_string_pretty(&classes)?)?;
+    Ok(())
+}
+
+/// whether `teacher` teaches a class `student` is in.
+pub async fn teaches(teacher: &Uuid, student: &Uuid) -> bool {
+    CLASSES
+        .read()
+        .await
+        .values()
+        .any(|class| class.teachers.contains(teacher) && class.students.contains(student))
+}
+
+pub async fn get_class(id: &Uuid) -> Option<Class> {
+    CLASSES.read().await.get(id).cloned()
+}
+
+/// the classes a user teaches, is in or is invited to; all of them for admins.
+pub async fn classes_of(user: &User) -> Vec<Class> {
+    CLASSES
+        .read()
+        .await
+        .values()
+        .filter(|class| class.is_member(user) || class.is_invited(user))
+        .cloned()
+        .collect()
+}
+
+pub async fn create_class(name: String, teachers: Vec<Uuid>) -> E<Class> {
+    if name.trim().is_empty() {
+        return Err(Er::new("class name required".to_string()));
+    }
+    let class = Class {
+        id: Uuid::new_v4(),
+        name: name.trim().to_string(),
+        teachers,
+        students: vec![],
+        invited: vec![],
+        created_at: Utc::now(),
+    };
+    let mut classes = CLASSES.write().await;
+    classes.insert(class.id, class.clone());
+    save_classes(&classes)?;
+    Ok(class)
+}
+
+/**
+ * add a user to a class as a teacher or a student, if they aren't in it
+ * already. Only for users who asked to join themselves, such as by
+ * launching the class from an LMS.
+ */
+pub async fn join(id: &Uuid, user_id: Uuid, teacher: bool) -> E<Class> {
+    let mut classes = CLASSES.write().await;
+    let class = classes
+        .get_mut(id)
+        .ok_or(Er::new(format!("no class {}", id)))?;
+    let members = if teacher {
+        &mut class.teachers
+    } else {
+        &mut class.students
+    };
+    if !members.contains(&user_id) {
+        members.push(user_id);
+    }
+    class.invited.retain(|x| *x != user_id);
+    let class = class.clone();
+    save_classes(&classes)?;
+    Ok(class)
+}
+
+/// change a class if `user` teaches it.
+async fn update_class<F>(id: &Uuid, user: &User, f: F) -> Result<Class, Rejection>
+where
+    F: FnOnce(&mut Class),
+{
+    let mut classes = CLASSES.write().await;
+    let class = classes.get_mut(id).ok_or(warp::reject::not_found())?;
+    if !class.is_teacher(user) {
+        return Err(warp::reject::custom(Forbidden));
+    }
+    f(class);
+    let class = class.clone();
+    if let Err(e) = save_classes(&classes) {
+        log::error!("Couldn't save classes: {}", e);
+        return Err(warp::reject());
+    }
+    Ok(class)
+}
+
+/// A class with its members spelt out, as the API shows it.
+#[derive(Serialize, ToSchema)]
+pub struct ClassInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub teachers: Vec<PublicUser>,
+    pub students: Vec<PublicUser>,
+    pub invited: Vec<PublicUser>,
+    pub created_at: DateTime<Utc>,
+}
+
+async fn public_users(ids: &[Uuid]) -> Vec<PublicUser> {
+    let mut users = vec![];
+    for id in ids.iter() {
+        if let Some(user) = auth::get_user(id).await {
+            users.push(user.public());
+        }
+    }
+    users
+}
+
+async fn describe(class: &Class) -> ClassInfo {
+    ClassInfo {
+        id: class.id,
+        name: class.name.clone(),
+        teachers: public_users(&class.teachers).await,
+        students: public_users(&class.students).await,
+        invited: public_users(&class.invited).await,
+        created_at: class.created_at,
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewClass {
+    name: String,
+}
+
+/// a user to add to a class, by id or username.
+#[derive(Deserialize, ToSchema)]
+pub struct Member {
+    id: Option<Uuid>,
+    username: Option<String>,
+}
+
+async fn find_member(member: Member) -> Result<User, Rejection> {
+    let user = match (member.id, member.username) {
+        (Some(id), _) => auth::get_user(&id).await,
+        (None, Some(username)) => auth::find_user_by(|user| user.username == username).await,
+        (None, None) => None,
+    };
+    user.ok_or(warp::reject::custom(BadRequest("no such user".to_string())))
+}
+
+/**
+ * the routes for managing classes:
+ * GET/POST /classes, GET /classes/<id>,
+ * POST /classes/<id>/students, DELETE /classes/<id>/students/<user id>,
+ * and the same for teachers. Students added by a teacher are invited, and
+ * join with POST /classes/<id>/join; admins add them directly.
+ */
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("classes"))
+        .and(require_user())
+        .and_then(|user: User| async move {
+            let mut classes = vec![];
+            for class in classes_of(&user).await.iter() {
+                classes.push(describe(class).await);
+            }
+            Ok::<_, Rejection>(warp::reply::json(&classes))
+        });
+
+    let create = warp::post()
+        .and(warp::path!("classes"))
+        .and(require_user())
+        .and(warp::body::json())
+        .and_then(|user: User, new: NewClass| async move {
+            if !user.is_teacher() {
+                return Err(warp::reject::custom(Forbidden));
+            }
+            let class = create_class(new.name, vec![user.id])
+                .await
+                .map_err(|e| warp::reject::custom(BadRequest(e.to_string())))?;
+            Ok(warp::reply::json(&describe(&class).await))
+        });
+
+    let show = warp::get()
+        .and(warp::path!("classes" / Uuid))
+        .and(require_user())
+        .and_then(|id: Uuid, user: User| async move {
+            match get_class(&id).await {
+                Some(class) if class.is_member(&user) || class.is_invited(&user) => {
+                    Ok(warp::reply::json(&describe(&class).await))
+                }
+                _ => Err(warp::reject::not_found()),
+            }
+        });
+
+    let add_student = warp::post()
+        .and(warp::path!("classes" / Uuid / "students"))
+        .and(require_user())
+        .and(warp::body::json())
+        .and_then(|id: Uuid, user: User, member: Member| async move {
+            let student = find_member(member).await?;
+            let class = update_class(&id, &user, |class| {
+                if class.students.contains(&student.id) {
+                    return;
+                }
+                if user.is_admin() {
+                    class.students.push(student.id);
+                } else if !class.invited.contains(&student.id) {
+                    class.invited.push(student.id);
+                }
+            })
+            .await?;
+            Ok::<_, Rejection>(warp::reply::json(&describe(&class).await))
+        });
+
+    let accept = warp::post()
+        .and(warp::path!("classes" / Uuid / "join"))
+        .and(require_user())
+        .and_then(|id: Uuid, user: User| async move {
+            match get_class(&id).await {
+                Some(class) if class.is_invited(&user) => (),
+                _ => return Err(warp::reject::not_found()),
+            }
+            let class = join(&id, user.id, false).await.map_err(|e| {
+                log::error!("Couldn't join class {}: {}", id, e);
+                warp::reject()
+            })?;
+            Ok(warp::reply::json(&describe(&class).await))
+        });
+
+    let remove_student = warp::delete()
+        .and(warp::path!("classes" / Uuid / "students" / Uuid))
+        .and(require_user())
+        .and_then(|id: Uuid, student: Uuid, user: User| async move {
+            let class = update_class(&id, &user, |class| {
+                class.students.retain(|x| *x != student);
+                class.invited.retain(|x| *x != student);
+            })
+            .await?;
+            Ok::<_, Rejection>(warp::reply::json(&describe(&class).await))
+        });
+
+    let add_teacher = warp::post()
+        .and(warp::path!("classes" / Uuid / "teachers"))
+        .and(require_user())
+        .and(warp::body::json())
+        .and_then(|id: Uuid, user: User, member: Member| async move {
+            let teacher = find_member(member).await?;
+            if !teacher.is_teacher() {
+                return Err(warp::reject::custom(BadRequest(format!(
+                    "{} is not a teacher",
+                    teacher.username
+                ))));
+            }
+            let class = update_class(&id, &user, |class| {
+                if !class.teachers.contains(&teacher.id) {
+                    class.teachers.push(teacher.id);
+                }
+            })
+            .await?;
+            Ok(warp::reply::json(&describe(&class).await))
+        });
+
+    let remove_teacher = warp::delete()
+        .and(warp::path!("classes" / Uuid / "teachers" / Uuid))
+        .and(require_user())
+        .and_then(|id: Uuid, teacher: Uuid, user: User| async move {
+            let class =
+                update_class(&id, &user, |class| class.teachers.retain(|x| *x != teacher)).await?;
+            Ok::<_, Rejection>(warp::reply::json(&describe(&class).await))
+        });
+
+    list.or(create)
+        .or(show)
+        .or(add_student)
+        .or(accept)
+        .or(remove_student)
+        .or(add_teacher)
+        .or(remove_teacher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::testing::{self, block_on};
+    use serde_json::json;
+    use warp::http::StatusCode;
+
+    async fn post(path: String, token: &str, user: Option<&User>) -> StatusCode {
+        let body = user.map(|user| json!({ "id": user.id }));
+        testing::status(&routes(), "POST", &path, token, body).await
+    }
+
+    #[test]
+    fn invited_students_join() {
+        let (teacher, teacher_token) = block_on(testing::user(Role::Teacher));
+        let (student, student_token) = block_on(testing::user(Role::Student));
+        block_on(async {
+            let class = create_class("class".to_string(), vec![teacher.id])
+                .await
+                .unwrap();
+            let students = format!("/classes/{}/students", class.id);
+            let status = post(students, &teacher_token, Some(&student)).await;
+            assert_eq!(status, StatusCode::OK);
+            let invited = get_class(&class.id).await.unwrap();
+            assert_eq!(invited.invited, vec![student.id]);
+            assert!(invited.students.is_empty());
+
+            let join = format!("/classes/{}/join", class.id);
+            assert_eq!(post(join, &student_token, None).await, StatusCode::OK);
+            let joined = get_class(&class.id).await.unwrap();
+            assert!(joined.invited.is_empty());
+            assert_eq!(joined.students, vec![student.id]);
+        });
+    }
+
+    #[test]
+    fn students_only_join_when_invited() {
+        let (teacher, _) = block_on(testing::user(Role::Teacher));
+        let (_, student_token) = block_on(testing::user(Role::Student));
+        block_on(async {
+            let class = create_class("class".to_string(), vec![teacher.id])
+                .await
+                .unwrap();
+            let join = format!("/classes/{}/join", class.id);
+            assert!(post(join, &student_token, None).await.is_client_error());
+            assert!(get_class(&class.id).await.unwrap().students.is_empty());
+        });
+    }
+
+    #[test]
+    fn admins_add_students_directly() {
+        let (teacher, _) = block_on(testing::user(Role::Teacher));
+        let (_, admin_token) = block_on(testing::user(Role::Admin));
+        let (student, _) = block_on(testing::user(Role::Student));
+        block_on(async {
+            let class = create_class("class".to_string(), vec![teacher.id])
+                .await
+                .unwrap();
+            let students = format!("/classes/{}/students", class.id);
+            let status = post(students, &admin_token, Some(&student)).await;
+            assert_eq!(status, StatusCode::OK);
+            let class = get_class(&class.id).await.unwrap();
+            assert_eq!(class.students, vec![student.id]);
+            assert!(class.invited.is_empty());
+        });
+    }
+
+    #[test]
+    fn only_teachers_of_the_class_invite() {
+        let (teacher, _) = block_on(testing::user(Role::Teacher));
+        let (_, other_teacher_token) = block_on(testing::user(Role::Teacher));
+        let (student, student_token) = block_on(testing::user(Role::Student));
+        let (friend, _) = block_on(testing::user(Role::Student));
+        block_on(async {
+            let class = create_class("class".to_string(), vec![teacher.id])
+                .await
+                .unwrap();
+            join(&class.id, student.id, false).await.unwrap();
+            let students = format!("/classes/{}/students", class.id);
+            for token in [&student_token, &other_teacher_token] {
+                let status = post(students.clone(), token, Some(&friend)).await;
+                assert_eq!(status, StatusCode::FORBIDDEN);
+            }
+            assert!(get_class(&class.id).await.unwrap().invited.is_empty());
+        });
+    }
+}
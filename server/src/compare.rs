@@ -1,13 +1,61 @@
 use askama::Template; // bring trait in scope
 use std::fs;
 
-use crate::session::find_session_with_uuid;
+use serde_json::json;
+
+use crate::annotations::{annotations_of, Anchor, Annotation};
+use crate::auth::{readable_session, User};
+use crate::session::SessionData;
 
 #[derive(Template)]
 #[template(path = "compare.html", escape = "none")]
 pub struct Comparison {
     source: String,
     dest: String,
+    annotations: Vec<AnnotationView>,
+}
+
+/// an annotation as shown below the comparison, already HTML escaped.
+struct AnnotationView {
+    category: String,
+    comment: String,
+    excerpt: String,
+    at: String,
+}
+
+impl AnnotationView {
+    fn new(annotation: &Annotation, session: &SessionData) -> Self {
+        let at = match annotation.anchor {
+            Anchor::Text {
+                sequence_number,
+                segment_number,
+                ..
+            } => format!("sequence {}, segment {}", sequence_number, segment_number),
+            Anchor::Time { start_ms, end_ms } => {
+                format!(
+                    "{:.1}s - {:.1}s",
+                    start_ms as f32 / 1000.0,
+                    end_ms as f32 / 1000.0
+                )
+            }
+        };
+        Self {
+            category: json!(annotation.category)
+                .as_str()
+                .unwrap_or("")
+                .to_string(),
+            comment: escape_html(&annotation.comment),
+            excerpt: escape_html(&annotation.excerpt(session).unwrap_or_default()),
+            at,
+        }
+    }
+}
+
+fn escape_html(from: &str) -> String {
+    from.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 fn escape(from: String) -> String {
@@ -16,23 +64,67 @@ fn escape(from: String) -> String {
         .replace('\"', "\\\"")
 }
 
+/// asset ids and languages name files, so they may only be `[A-Za-z0-9_-]+`.
+fn is_name(x: &str) -> bool {
+    !x.is_empty()
+        && x.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// the text of an asset in the given language.
+pub fn reference(asset_id: &str, lang: &str) -> Option<String> {
+    if !is_name(asset_id) || !is_name(lang) {
+        return None;
+    }
+    fs::read_to_string(format!("../client/assets/{}/{}.txt", asset_id, lang)).ok()
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|word| {
+            word.trim_matches(|c: char| !c.is_alphanumeric())
+                .to_lowercase()
+        })
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/**
+ * an automatic score for a transcript against the reference text, between 0
+ * and 1: one minus the word error rate, ignoring case and punctuation.
+ */
+pub fn score(reference: &str, transcript: &str) -> f32 {
+    let reference = words(reference);
+    let transcript = words(transcript);
+    if reference.is_empty() {
+        return 0.0;
+    }
+    // edit distance between the word lists, one row at a time.
+    let mut previous: Vec<usize> = (0..=transcript.len()).collect();
+    for (i, r) in reference.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, t) in transcript.iter().enumerate() {
+            let substitution = previous[j] + usize::from(r != t);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    let errors = previous[transcript.len()] as f32;
+    (1.0 - errors / reference.len() as f32).max(0.0)
+}
+
 pub async fn compare(
     asset_id: String,
     uuid: String,
     lang: String,
+    user: Option<User>,
 ) -> std::result::Result<impl warp::Reply, warp::Rejection> {
-    let source = match fs::read_to_string(format!("../client/assets/{}/{}.txt", asset_id, lang)) {
-        Ok(x) => escape(x),
-        Err(_) => return Err(warp::reject::not_found()),
+    let source = match reference(&asset_id, &lang) {
+        Some(x) => escape(x),
+        None => return Err(warp::reject::not_found()),
     };
 
-    let session_id = find_session_with_uuid(&uuid)
-        .await
-        .ok_or(warp::reject::not_found())?;
-
-    let session = crate::session::get_session(&session_id)
-        .await
-        .ok_or(warp::reject::not_found())?;
+    let (_, session) = readable_session(&uuid, &user).await?;
 
     let dest = match session.transcript() {
         Ok(e) => escape(e.to_string()),
@@ -42,7 +134,17 @@ pub async fn compare(
         }
     };
 
-    let template = Comparison { source, dest };
+    let annotations = annotations_of(&session.uuid)
+        .await
+        .iter()
+        .map(|annotation| AnnotationView::new(annotation, &session))
+        .collect();
+
+    let template = Comparison {
+        source,
+        dest,
+        annotations,
+    };
 
     Ok(warp::reply::html(template.render().unwrap()))
 }
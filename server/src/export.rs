@@ -0,0 +1,408 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::session::SessionData;
+use crate::translate::{TranslationResponse, Word};
+
+/// what text and subtitles show where a transcription is missing.
+const GAP: &str = "[untranscribed]";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Txt,
+    Srt,
+    Vtt,
+    Json,
+}
+
+impl Format {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "txt" => Some(Format::Txt),
+            "srt" => Some(Format::Srt),
+            "vtt" => Some(Format::Vtt),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Txt => "txt",
+            Format::Srt => "srt",
+            Format::Vtt => "vtt",
+            Format::Json => "json",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Txt => "text/plain; charset=utf-8",
+            Format::Srt => "application/x-subrip; charset=utf-8",
+            Format::Vtt => "text/vtt; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+}
+
+/// A session's transcript with its timing. Times are milliseconds from the
+/// start of the session, and are missing for audio not yet sent.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Transcript {
+    pub uuid: Uuid,
+    pub language: String,
+    pub resource: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub sequences: Vec<Sequence>,
+    /// the sequences with no transcription or segments missing.
+    pub gaps: Vec<usize>,
+}
+
+/// A chunk of audio, cut at a silence, and what it was transcribed as.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Sequence {
+    pub sequence_number: usize,
+    pub start_ms: Option<u64>,
+    pub end_ms: Option<u64>,
+    /// nothing came back for the sequence, or some of its segments are
+    /// missing: it failed, or is still being transcribed.
+    pub gap: bool,
+    /// empty for silence.
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Segment {
+    pub segment_number: usize,
+    /// for a missing segment, the time between its neighbours.
+    pub start_ms: Option<u64>,
+    pub end_ms: Option<u64>,
+    /// `null` where the segment is missing.
+    pub text: Option<String>,
+    /// with times from the start of the session.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub words: Option<Vec<Word>>,
+}
+
+/// where each sequence starts and ends, in milliseconds, as far as known.
+fn sequence_times(session: &SessionData) -> Vec<(u64, u64)> {
+    let rate = session.sample_rate.max(1) as u64;
+    let mut samples = 0u64;
+    let mut times = vec![];
+    for length in session.sequence_samples.iter() {
+        let start = samples * 1000 / rate;
+        samples += *length as u64;
+        times.push((start, samples * 1000 / rate));
+    }
+    times
+}
+
+/// a response's times, moved from its sequence to the session.
+fn segment(response: &TranslationResponse, start: u64, end: u64) -> Segment {
+    let at = |ms: i64| (start + ms.max(0) as u64).min(end);
+    Segment {
+        segment_number: response.segment_number as usize,
+        start_ms: Some(at(response.segment_start)),
+        end_ms: Some(at(response.segment_end)),
+        text: Some(response.translation.trim().to_string()),
+        words: response.words.as_ref().map(|words| {
+            words
+                .iter()
+                .map(|word| Word {
+                    start: at(word.start) as i64,
+                    end: at(word.end) as i64,
+                    ..word.clone()
+                })
+                .collect()
+        }),
+    }
+}
+
+fn sequence(
+    sequence_number: usize,
+    responses: Option<&Vec<Option<TranslationResponse>>>,
+    times: Option<(u64, u64)>,
+) -> Sequence {
+    let Some(responses) = responses else {
+        return Sequence {
+            sequence_number,
+            start_ms: times.map(|x| x.0),
+            end_ms: times.map(|x| x.1),
+            gap: true,
+            segments: vec![],
+        };
+    };
+    let mut segments: Vec<Segment> = responses
+        .iter()
+        .enumerate()
+        .map(|(segment_number, response)| match (response, times) {
+            (Some(response), Some((start, end))) => segment(response, start, end),
+            (Some(response), None) => Segment {
+                start_ms: None,
+                end_ms: None,
+                ..segment(response, 0, u64::MAX)
+            },
+            (None, _) => Segment {
+                segment_number,
+                start_ms: None,
+                end_ms: None,
+                text: None,
+                words: None,
+            },
+        })
+        .collect();
+    // missing segments span the time between the ones around them.
+    if let Some((start, end)) = times {
+        for i in 0..segments.len() {
+            if segments[i].text.is_some() {
+                continue;
+            }
+            let before = segments[..i].iter().rev().find_map(|x| x.end_ms);
+            let after = segments[i + 1..].iter().find_map(|x| x.start_ms);
+            let before = before.unwrap_or(start);
+            segments[i].start_ms = Some(before);
+            segments[i].end_ms = Some(after.unwrap_or(end).max(before));
+        }
+    }
+    Sequence {
+        sequence_number,
+        start_ms: times.map(|x| x.0),
+        end_ms: times.map(|x| x.1),
+        gap: segments.iter().any(|x| x.text.is_none()),
+        segments,
+    }
+}
+
+pub fn transcript(session: &SessionData) -> Transcript {
+    let times = sequence_times(session);
+    let translations = session.translations.lock().unwrap();
+    let sequences = translations
+        .sequences()
+        .iter()
+        .enumerate()
+        .map(|(i, responses)| sequence(i, responses.as_ref(), times.get(i).copied()))
+        .collect();
+    Transcript {
+        uuid: session.uuid,
+        language: session.language.clone(),
+        resource: session.resource.clone(),
+        created_at: session.created_at,
+        sequences,
+        gaps: translations.gaps(),
+    }
+}
+
+/// one line per sequence, gaps shown as `[untranscribed]`.
+fn text(transcript: &Transcript) -> String {
+    let mut result = String::new();
+    for sequence in transcript.sequences.iter() {
+        let line = if sequence.segments.is_empty() && sequence.gap {
+            GAP.to_string()
+        } else {
+            sequence
+                .segments
+                .iter()
+                .map(|x| x.text.as_deref().unwrap_or(GAP))
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<&str>>()
+                .join(" ")
+        };
+        if !line.is_empty() {
+            result.push_str(&line);
+            result.push('\n');
+        }
+    }
+    result
+}
+
+/// the subtitles: each segment with its times, and gaps where the audio
+/// wasn't transcribed.
+fn cues(transcript: &Transcript) -> Vec<(u64, u64, String)> {
+    let mut cues = vec![];
+    for sequence in transcript.sequences.iter() {
+        if sequence.segments.is_empty() {
+            if let (true, Some(start), Some(end)) =
+                (sequence.gap, sequence.start_ms, sequence.end_ms)
+            {
+                cues.push((start, end, GAP.to_string()));
+            }
+            continue;
+        }
+        for segment in sequence.segments.iter() {
+            let (Some(start), Some(end)) = (segment.start_ms, segment.end_ms) else {
+                continue;
+            };
+            let text = segment.text.as_deref().unwrap_or(GAP);
+            if !text.is_empty() {
+                cues.push((start, end.max(start), text.to_string()));
+            }
+        }
+    }
+    cues.sort_by_key(|x| x.0);
+    cues
+}
+
+fn timestamp(ms: u64, separator: char) -> String {
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        separator,
+        ms % 1000
+    )
+}
+
+fn srt(transcript: &Transcript) -> String {
+    let mut result = String::new();
+    for (i, (start, end, text)) in cues(transcript).iter().enumerate() {
+        result.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            timestamp(*start, ','),
+            timestamp(*end, ','),
+            text
+        ));
+    }
+    result
+}
+
+fn vtt(transcript: &Transcript) -> String {
+    let mut result = "WEBVTT\n\n".to_string();
+    for (start, end, text) in cues(transcript).iter() {
+        let text = text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        result.push_str(&format!(
+            "{} --> {}\n{}\n\n",
+            timestamp(*start, '.'),
+            timestamp(*end, '.'),
+            text
+        ));
+    }
+    result
+}
+
+pub fn render(transcript: &Transcript, format: Format) -> String {
+    match format {
+        Format::Txt => text(transcript),
+        Format::Srt => srt(transcript),
+        Format::Vtt => vtt(transcript),
+        Format::Json => serde_json::to_string_pretty(transcript).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session;
+    use crate::translate::TranslationResponses;
+    use std::sync::{Arc, Mutex};
+
+    fn response(
+        sequence_number: usize,
+        segment: (i32, i32),
+        start: i64,
+        end: i64,
+        text: &str,
+    ) -> TranslationResponse {
+        TranslationResponse {
+            sequence_number,
+            translation: text.to_string(),
+            num_segments: segment.1,
+            segment_number: segment.0,
+            segment_start: start,
+            segment_end: end,
+            uuid: String::new(),
+            words: None,
+        }
+    }
+
+    /// a session with sequences of the given number of samples at `rate`.
+    fn session(rate: u32, samples: &[usize], responses: &[TranslationResponse]) -> SessionData {
+        let (session_id, _rx) = session::test_session("de");
+        let mut session = session::get_session_sync(&session_id).unwrap();
+        session.sample_rate = rate;
+        session.sequence_samples = samples.to_vec();
+        let mut translations = TranslationResponses::new();
+        for response in responses {
+            translations.add_translation(response).unwrap();
+        }
+        session.translations = Arc::new(Mutex::new(translations));
+        session
+    }
+
+    #[test]
+    fn timestamps_roll_over_into_hours() {
+        assert_eq!(timestamp(0, ','), "00:00:00,000");
+        assert_eq!(timestamp(3_599_999, ','), "00:59:59,999");
+        assert_eq!(timestamp(3_600_000, '.'), "01:00:00.000");
+        assert_eq!(timestamp(90_061_001, '.'), "25:01:01.001");
+    }
+
+    #[test]
+    fn sequence_times_add_up_without_drifting() {
+        // a third of a second each, which isn't a whole number of ms.
+        let session = session(44100, &[14_700, 14_700, 14_700], &[]);
+        assert_eq!(
+            sequence_times(&session),
+            vec![(0, 333), (333, 666), (666, 1000)]
+        );
+    }
+
+    #[test]
+    fn segments_are_placed_in_their_sequence() {
+        let session = session(
+            16000,
+            &[16000, 8000, 32000],
+            &[
+                response(0, (0, 1), 0, 900, " eins"),
+                response(1, (0, 1), 100, 400, "zwei"),
+                // runs past the end of the sequence.
+                response(2, (0, 1), 500, 2500, "drei & <vier>"),
+            ],
+        );
+        let transcript = transcript(&session);
+        assert_eq!(
+            render(&transcript, Format::Srt),
+            "1\n00:00:00,000 --> 00:00:00,900\neins\n\n\
+             2\n00:00:01,100 --> 00:00:01,400\nzwei\n\n\
+             3\n00:00:02,000 --> 00:00:03,500\ndrei & <vier>\n\n"
+        );
+        assert_eq!(
+            render(&transcript, Format::Vtt),
+            "WEBVTT\n\n\
+             00:00:00.000 --> 00:00:00.900\neins\n\n\
+             00:00:01.100 --> 00:00:01.400\nzwei\n\n\
+             00:00:02.000 --> 00:00:03.500\ndrei &amp; &lt;vier&gt;\n\n"
+        );
+    }
+
+    #[test]
+    fn missing_sequences_and_segments_are_gaps() {
+        let session = session(
+            1000,
+            &[1000, 2000, 3000],
+            &[
+                response(0, (0, 1), 0, 1000, "eins"),
+                response(2, (1, 2), 2000, 3000, "drei"),
+            ],
+        );
+        let transcript = transcript(&session);
+        assert_eq!(transcript.gaps, vec![1, 2]);
+        assert_eq!(
+            render(&transcript, Format::Txt),
+            "eins\n[untranscribed]\n[untranscribed] drei\n"
+        );
+        assert_eq!(
+            render(&transcript, Format::Srt),
+            "1\n00:00:00,000 --> 00:00:01,000\neins\n\n\
+             2\n00:00:01,000 --> 00:00:03,000\n[untranscribed]\n\n\
+             3\n00:00:03,000 --> 00:00:05,000\n[untranscribed]\n\n\
+             4\n00:00:05,000 --> 00:00:06,000\ndrei\n\n"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use serde::Serialize;
+use std::sync::Arc;
+use warp::http::StatusCode;
+
+use crate::queue::TranslationQueue;
+use crate::router::Router;
+
+#[derive(Serialize)]
+struct Check {
+    name: String,
+    ok: bool,
+    detail: String,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    checks: Vec<Check>,
+}
+
+impl Readiness {
+    fn check(&mut self, name: &str, ok: bool, detail: String) {
+        self.ready &= ok;
+        self.checks.push(Check {
+            name: name.to_string(),
+            ok,
+            detail,
+        });
+    }
+}
+
+fn recordings_writable() -> (bool, String) {
+    let Ok(dir) = std::env::var("RECORDINGS_DIR") else {
+        return (true, "RECORDINGS_DIR not set, not recording".to_string());
+    };
+    let probe = format!("{}/.readyz", dir);
+    match std::fs::write(&probe, b"").and_then(|_| std::fs::remove_file(&probe)) {
+        Ok(_) => (true, format!("{} is writable", dir)),
+        Err(e) => (false, format!("{} is not writable: {}", dir, e)),
+    }
+}
+
+/**
+ * whether the server can take sessions: the local model is loaded (if it is
+ * used), workers are taking requests, remote backends passed their last
+ * health check, and recordings can be written.
+ */
+pub fn readyz(queue: &TranslationQueue, router: &Arc<Router>) -> impl warp::Reply {
+    let mut readiness = Readiness {
+        ready: true,
+        checks: vec![],
+    };
+    for backend in router.backends() {
+        if backend.name == "whispercpp" {
+            let loaded = crate::whispercpp::model_loaded();
+            let detail = if loaded { "loaded" } else { "loading" };
+            readiness.check("whispercpp_model", loaded, detail.to_string());
+        } else {
+            let healthy = backend.is_healthy();
+            let detail = if healthy { "reachable" } else { "unreachable" };
+            readiness.check(&backend.name, healthy, detail.to_string());
+        }
+    }
+    if router.backends().is_empty() {
+        readiness.check("backends", false, "no backend configured".to_string());
+    }
+    let workers = queue.workers();
+    readiness.check("workers", workers > 0, format!("{} running", workers));
+    let (writable, detail) = recordings_writable();
+    readiness.check("recordings_dir", writable, detail);
+
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    warp::reply::with_status(warp::reply::json(&readiness), status)
+}
+
+/// the process is up and serving requests.
+pub fn healthz() -> impl warp::Reply {
+    warp::reply::json(&serde_json::json!({ "alive": true }))
+}
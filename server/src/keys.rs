@@ -0,0 +1,177 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::{self, require_user, BadRequest, User};
+use crate::error::{Er, E};
+
+/// what API keys start with, to tell them apart from login tokens.
+const PREFIX: &str = "tlk_";
+
+/// A key another server uses to call the API on behalf of a user.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    /// SHA-256 of the key; the key itself is only shown when it's created.
+    hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ApiKey {
+    /// what the API shows of a key.
+    pub fn public(&self) -> KeyInfo {
+        KeyInfo {
+            id: self.id,
+            name: self.name.clone(),
+            created_at: self.created_at,
+            key: None,
+        }
+    }
+}
+
+/// An API key as the API shows it.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct KeyInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// the key itself, only when it's created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+lazy_static! {
+    static ref KEYS: RwLock<Vec<ApiKey>> = RwLock::new(load_keys());
+}
+
+fn keys_file() -> String {
+    std::env::var("API_KEYS_FILE").unwrap_or("api_keys.json".to_string())
+}
+
+fn load_keys() -> Vec<ApiKey> {
+    match std::fs::read_to_string(keys_file()) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            log::error!("Couldn't parse {}: {}", keys_file(), e);
+            vec![]
+        }),
+        Err(_) => vec![],
+    }
+}
+
+fn save_keys(keys: &[ApiKey]) -> E<()> {
+    std::fs::write(keys_file(), serde_json::to_string_pretty(keys)?)?;
+    Ok(())
+}
+
+fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// whether a token from a request is an API key rather than a login token.
+pub fn is_key(token: &str) -> bool {
+    token.starts_with(PREFIX)
+}
+
+/// the user an API key acts for. Keys are `tlk_<id><secret>`.
+pub async fn user_for_key(key: &str) -> Option<User> {
+    let id = Uuid::parse_str(key.strip_prefix(PREFIX)?.get(..32)?).ok()?;
+    let user_id = KEYS
+        .read()
+        .await
+        .iter()
+        .find(|x| x.id == id && x.hash == hash_key(key))?
+        .user_id;
+    auth::get_user(&user_id).await
+}
+
+/// make a key for a user, returning it with the only copy of the secret.
+async fn create_key(name: String, user: &User) -> E<(ApiKey, String)> {
+    if name.trim().is_empty() {
+        return Err(Er::new("key name required".to_string()));
+    }
+    let id = Uuid::new_v4();
+    let key = format!(
+        "{}{}{}{}",
+        PREFIX,
+        id.simple(),
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    );
+    let api_key = ApiKey {
+        id,
+        user_id: user.id,
+        name: name.trim().to_string(),
+        hash: hash_key(&key),
+        created_at: Utc::now(),
+    };
+    let mut keys = KEYS.write().await;
+    keys.push(api_key.clone());
+    save_keys(&keys)?;
+    Ok((api_key, key))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewKey {
+    name: String,
+}
+
+/**
+ * the routes for API keys: GET/POST /keys and DELETE /keys/<id>. Users
+ * manage their own keys; the key is only in the reply to the POST.
+ */
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("keys"))
+        .and(require_user())
+        .and_then(|user: User| async move {
+            let keys: Vec<KeyInfo> = KEYS
+                .read()
+                .await
+                .iter()
+                .filter(|x| x.user_id == user.id)
+                .map(|x| x.public())
+                .collect();
+            Ok::<_, Rejection>(warp::reply::json(&keys))
+        });
+
+    let create = warp::post()
+        .and(warp::path!("keys"))
+        .and(require_user())
+        .and(warp::body::json())
+        .and_then(|user: User, new: NewKey| async move {
+            let (api_key, key) = create_key(new.name, &user)
+                .await
+                .map_err(|e| warp::reject::custom(BadRequest(e.to_string())))?;
+            let reply = KeyInfo {
+                key: Some(key),
+                ..api_key.public()
+            };
+            Ok::<_, Rejection>(warp::reply::json(&reply))
+        });
+
+    let delete = warp::delete()
+        .and(warp::path!("keys" / Uuid))
+        .and(require_user())
+        .and_then(|id: Uuid, user: User| async move {
+            let mut keys = KEYS.write().await;
+            let index = keys
+                .iter()
+                .position(|x| x.id == id && (x.user_id == user.id || user.is_admin()))
+                .ok_or(warp::reject::not_found())?;
+            keys.remove(index);
+            if let Err(e) = save_keys(&keys) {
+                log::error!("Couldn't save API keys: {}", e);
+                return Err(warp::reject());
+            }
+            Ok(warp::reply())
+        });
+
+    list.or(create).or(delete)
+}
@@ -0,0 +1,255 @@
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::{Filter, Rejection};
+
+use crate::tls::PeerAddr;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Who is connecting: the logged in user, if any, and where from.
+#[derive(Clone, Debug)]
+pub struct Client {
+    pub owner: Option<Uuid>,
+    pub ip: Option<IpAddr>,
+}
+
+impl Client {
+    /// what audio quotas are counted against: the user, or the address of
+    /// anonymous clients.
+    pub fn quota_key(&self) -> String {
+        match (self.owner, self.ip) {
+            (Some(owner), _) => format!("user:{}", owner),
+            (None, Some(ip)) => format!("ip:{}", ip),
+            (None, None) => "anonymous".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TooManyRequests(pub String);
+impl warp::reject::Reject for TooManyRequests {}
+
+/// audio transcribed for a key on one day.
+struct Usage {
+    day: NaiveDate,
+    audio_seconds: f64,
+}
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<HashMap<String, VecDeque<Instant>>> = Mutex::new(HashMap::new());
+    static ref USAGE: Mutex<HashMap<String, Usage>> = Mutex::new(HashMap::new());
+}
+
+/// a limit from the environment, 0 meaning unlimited.
+fn env_limit(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(default)
+}
+
+pub fn daily_audio_seconds() -> u64 {
+    env_limit("DAILY_AUDIO_SECONDS", 7200)
+}
+
+pub fn max_session_seconds() -> u64 {
+    env_limit("MAX_SESSION_SECONDS", 7200)
+}
+
+pub fn max_message_bytes() -> usize {
+    match env_limit("MAX_MESSAGE_BYTES", 1024 * 1024) {
+        0 => usize::MAX,
+        x => x as usize,
+    }
+}
+
+/**
+ * the address of the client. Behind a reverse proxy set
+ * `TRUST_FORWARDED_FOR=true` to take it from `X-Forwarded-For` instead.
+ */
+pub fn client_ip() -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddr>())
+        .map(|remote: Option<SocketAddr>, peer: Option<PeerAddr>| remote.or(peer.map(|x| x.0)))
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(|remote: Option<SocketAddr>, forwarded: Option<String>| {
+            let trusted = std::env::var("TRUST_FORWARDED_FOR")
+                .map(|x| x == "true" || x == "1")
+                .unwrap_or(false);
+            match forwarded {
+                Some(forwarded) if trusted => forwarded
+                    .split(',')
+                    .next()
+                    .and_then(|x| x.trim().parse().ok()),
+                _ => remote.map(|x| x.ip()),
+            }
+        })
+}
+
+/**
+ * count a new websocket connection, unless the client's address or user
+ * already made `CONNECTIONS_PER_MINUTE_PER_IP` (default 60) or
+ * `CONNECTIONS_PER_MINUTE_PER_USER` (default 10) in the last minute.
+ */
+pub fn allow_connection(client: &Client) -> bool {
+    allow_connection_at(client, Instant::now())
+}
+
+fn allow_connection_at(client: &Client, now: Instant) -> bool {
+    let mut limits = vec![];
+    if let Some(ip) = client.ip {
+        limits.push((
+            format!("ip:{}", ip),
+            env_limit("CONNECTIONS_PER_MINUTE_PER_IP", 60),
+        ));
+    }
+    if let Some(owner) = client.owner {
+        limits.push((
+            format!("user:{}", owner),
+            env_limit("CONNECTIONS_PER_MINUTE_PER_USER", 10),
+        ));
+    }
+
+    let mut connections = CONNECTIONS.lock().unwrap();
+    for (key, limit) in limits.iter() {
+        let recent = connections.entry(key.clone()).or_default();
+        while recent.front().is_some_and(|x| now - *x > WINDOW) {
+            recent.pop_front();
+        }
+        if *limit > 0 && recent.len() as u64 >= *limit {
+            log::info!("Too many connections from {}", key);
+            return false;
+        }
+    }
+    for (key, _) in limits.iter() {
+        connections.entry(key.clone()).or_default().push_back(now);
+    }
+    connections.retain(|_, recent| !recent.is_empty());
+    true
+}
+
+fn used_today(usage: &HashMap<String, Usage>, key: &str) -> f64 {
+    match usage.get(key) {
+        Some(x) if x.day == Utc::now().date_naive() => x.audio_seconds,
+        _ => 0.0,
+    }
+}
+
+/// whether the key has some of today's audio quota left.
+pub fn has_quota(key: &str) -> bool {
+    let limit = daily_audio_seconds();
+    limit == 0 || used_today(&USAGE.lock().unwrap(), key) < limit as f64
+}
+
+/**
+ * count seconds of audio against the key's quota for today. Returns false,
+ * without counting them, if they would take it over `DAILY_AUDIO_SECONDS`.
+ */
+pub fn charge_audio(key: &str, seconds: f64) -> bool {
+    let limit = daily_audio_seconds();
+    let mut usage = USAGE.lock().unwrap();
+    let used = used_today(&usage, key);
+    if limit > 0 && used + seconds > limit as f64 {
+        return false;
+    }
+    usage.insert(
+        key.to_string(),
+        Usage {
+            day: Utc::now().date_naive(),
+            audio_seconds: used + seconds,
+        },
+    );
+    true
+}
+
+/// The state of a user's quotas; limits that are off are null.
+#[derive(Serialize, ToSchema)]
+pub struct Quota {
+    pub audio_seconds_used: u64,
+    pub audio_seconds_limit: Option<u64>,
+    pub audio_seconds_remaining: Option<u64>,
+    pub resets_at: Option<DateTime<Utc>>,
+    pub max_session_seconds: Option<u64>,
+    pub max_message_bytes: usize,
+}
+
+/// the state of a key's quotas, as shown to the user.
+pub fn quota(key: &str) -> Quota {
+    let used = used_today(&USAGE.lock().unwrap(), key);
+    let limit = daily_audio_seconds();
+    let resets_at = Utc::now()
+        .date_naive()
+        .checked_add_days(Days::new(1))
+        .and_then(|x| x.and_hms_opt(0, 0, 0))
+        .map(|x| x.and_utc());
+    Quota {
+        audio_seconds_used: used.round() as u64,
+        audio_seconds_limit: (limit > 0).then_some(limit),
+        audio_seconds_remaining: (limit > 0).then(|| (limit as f64 - used).max(0.0).round() as u64),
+        resets_at,
+        max_session_seconds: (max_session_seconds() > 0).then(max_session_seconds),
+        max_message_bytes: max_message_bytes(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::block_on;
+    use std::net::Ipv4Addr;
+
+    fn client(ip: &str) -> Client {
+        Client {
+            owner: None,
+            ip: Some(ip.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn connections_per_address_are_limited_for_a_minute() {
+        let client = client("192.0.2.1");
+        let start = Instant::now();
+        for _ in 0..60 {
+            assert!(allow_connection_at(&client, start));
+        }
+        assert!(!allow_connection_at(&client, start));
+        assert!(allow_connection_at(&self::client("192.0.2.2"), start));
+        let later = start + WINDOW + Duration::from_secs(1);
+        assert!(allow_connection_at(&client, later));
+    }
+
+    #[test]
+    fn audio_is_refused_once_the_quota_is_used() {
+        let key = "ip:192.0.2.3";
+        assert!(charge_audio(key, 7000.0));
+        assert!(!charge_audio(key, 300.0));
+        assert!(has_quota(key));
+        assert!(charge_audio(key, 200.0));
+        assert!(!has_quota(key));
+        assert!(!charge_audio(key, 1.0));
+        assert_eq!(quota(key).audio_seconds_remaining, Some(0));
+        assert!(has_quota("ip:192.0.2.4"));
+    }
+
+    #[test]
+    fn forwarded_addresses_are_only_trusted_when_configured() {
+        let ip = || {
+            let request = warp::test::request()
+                .remote_addr(SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 4000)))
+                .header("x-forwarded-for", "203.0.113.7, 10.0.0.2");
+            block_on(request.filter(&client_ip())).unwrap()
+        };
+        assert_eq!(ip(), Some("10.0.0.1".parse().unwrap()));
+        std::env::set_var("TRUST_FORWARDED_FOR", "true");
+        let trusted = ip();
+        std::env::remove_var("TRUST_FORWARDED_FOR");
+        assert_eq!(trusted, Some("203.0.113.7".parse().unwrap()));
+    }
+}
@@ -1,20 +1,45 @@
 #![feature(let_chains)]
 #![feature(async_closure)]
+#![recursion_limit = "256"]
 
+mod annotations;
 mod api;
+mod assignments;
+mod auth;
+mod classes;
 mod compare;
 mod error;
+mod export;
+mod health;
+mod keys;
+mod limits;
+mod lti;
+mod messages;
+mod metrics;
+mod openai;
+mod openapi;
 mod queue;
+mod router;
 mod session;
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod tls;
 mod translate;
+mod webhooks;
 mod whispercpp;
 mod whisperx;
 
-use crossbeam_channel::unbounded;
 use dotenv::dotenv;
-use thread_priority::*;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
 
 use crate::api::serve;
+use crate::openai::OpenAi;
+use crate::queue::TranslationQueue;
+use crate::router::{Backend, Router};
+use crate::translate::remote_capacity;
+use crate::whispercpp::WhisperCpp;
 use crate::whisperx::WhisperX;
 
 pub const LOWER_PRIORITY: u8 = 40;
@@ -24,28 +49,54 @@ pub const HIGHER_PRIORITY: u8 = 60;
 async fn main() {
     dotenv().ok();
 
-    env_logger::init();
+    telemetry::init().unwrap();
 
-    let (translate_tx, translate_rx) = unbounded();
-    log::debug!("Making transcription pool");
-    whispercpp::start_translate_pool().unwrap();
-    log::debug!("Made WhisperCpp pool");
-    let queue = queue::get_queue();
+    let queue = TranslationQueue::new().unwrap();
+    let mut router = Router::new();
+    let local_workers = whispercpp::num_processes();
+    if local_workers > 0 {
+        router.add(Backend::new("whispercpp", WhisperCpp {}, local_workers));
+        std::thread::spawn(whispercpp::load_model);
+    }
     if std::env::var("WHISPER_SERVER").is_ok() {
-        std::thread::spawn(async move || {
-            set_current_thread_priority(ThreadPriority::Crossplatform(
-                HIGHER_PRIORITY.try_into().unwrap(),
-            ))
-            .unwrap();
-            let mut queue = queue::get_queue();
-            let whisperx = WhisperX::new().unwrap();
-            log::debug!("Waiting for WhisperX job");
-            queue.subscribe::<WhisperX>(&whisperx).unwrap();
-        });
-        log::debug!("Started remote whisper process");
+        let whisperx = WhisperX::new().unwrap();
+        router.add(Backend::new("whisperx", whisperx, remote_capacity("whisperx")));
     }
+    if std::env::var("OPENAI_SERVER").is_ok() {
+        let openai = OpenAi::new().unwrap();
+        router.add(Backend::new("openai", openai, remote_capacity("openai")));
+    }
+    let remote_workers: usize = router
+        .backends()
+        .iter()
+        .filter(|backend| backend.name != "whispercpp")
+        .map(|backend| backend.capacity())
+        .sum();
+    let router = Arc::new(router);
 
-    std::thread::spawn(async move || queue.queue_process(translate_rx).await);
-    log::debug!("Made enqueuing process");
-    serve(translate_tx).await;
+    log::debug!("Making transcription pool");
+    router::start_pool(router.clone(), queue.clone(), local_workers, LOWER_PRIORITY).unwrap();
+    router::start_pool(
+        router.clone(),
+        queue.clone(),
+        remote_workers,
+        HIGHER_PRIORITY,
+    )
+    .unwrap();
+    router::start_health_checks(router.clone());
+    session::start_expiry();
+    log::debug!("Made transcription pool");
+    serve(queue.clone(), router, shutdown_signal()).await;
+    log::info!("Stopped accepting connections, draining");
+    session::shutdown(&queue).await;
+    telemetry::shutdown();
+}
+
+/// completes on SIGTERM or ctrl-c.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = terminate.recv() => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
 }
@@ -0,0 +1,51 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::ws::Message;
+
+use crate::translate::TranslationResponse;
+
+/// Why a session isn't being transcribed (yet).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// waiting for transcription capacity.
+    Waitlisted,
+    /// not admitted, the connection is closed.
+    Refused,
+    /// the server is stopping and finishing what it has.
+    ShuttingDown,
+}
+
+/// What the server sends on the /chat websocket, as JSON text messages. The
+/// client sends its audio as binary messages of 32 bit little-endian floats.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum ServerMessage {
+    /// the first message, with the uuid to use with the HTTP API.
+    Started { uuid: Uuid },
+    /// a transcribed segment.
+    Transcription(TranslationResponse),
+    Status {
+        status: Status,
+        reason: String,
+        /// seconds, while waitlisted.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        estimated_latency: Option<u64>,
+    },
+    /// a chunk of audio couldn't be transcribed.
+    Failed {
+        uuid: Uuid,
+        failed_sequence_number: usize,
+        error: String,
+    },
+    /// `reason` is the limit reached, which stops the session, or
+    /// `queue_full` when a chunk of audio couldn't be queued and was dropped.
+    Error { error: String, reason: String },
+}
+
+impl ServerMessage {
+    pub fn to_message(&self) -> Message {
+        Message::text(serde_json::to_string(self).unwrap_or_default())
+    }
+}
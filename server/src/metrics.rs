@@ -0,0 +1,83 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::error::E;
+use crate::queue::TranslationQueue;
+
+lazy_static! {
+    pub static ref ACTIVE_SESSIONS: IntGauge = register_int_gauge!(
+        "terplounge_active_sessions",
+        "Sessions which are still being transcribed"
+    )
+    .unwrap();
+    pub static ref QUEUE_LENGTH: IntGauge = register_int_gauge!(
+        "terplounge_queue_length",
+        "Chunks waiting for transcription"
+    )
+    .unwrap();
+    pub static ref CANCELLED: IntCounter = register_int_counter!(
+        "terplounge_cancelled_chunks_total",
+        "Chunks dropped or abandoned because their session was cancelled"
+    )
+    .unwrap();
+    pub static ref TRANSCRIPTION_SECONDS: HistogramVec = register_histogram_vec!(
+        "terplounge_transcription_seconds",
+        "Time taken to transcribe a chunk, by backend",
+        &["backend"],
+        exponential_buckets(0.25, 2.0, 10).unwrap()
+    )
+    .unwrap();
+    pub static ref REAL_TIME_FACTOR: HistogramVec = register_histogram_vec!(
+        "terplounge_real_time_factor",
+        "Transcription time divided by audio length, by backend",
+        &["backend"],
+        vec![0.05, 0.1, 0.25, 0.5, 0.75, 1.0, 1.5, 2.0, 4.0]
+    )
+    .unwrap();
+    pub static ref CHUNK_SECONDS: Histogram = register_histogram!(
+        "terplounge_chunk_seconds",
+        "Length of the audio chunks sent for transcription",
+        vec![5.0, 10.0, 15.0, 20.0, 30.0, 45.0, 60.0, 120.0]
+    )
+    .unwrap();
+    pub static ref FAILURES: IntCounterVec =
+        register_int_counter_vec!("terplounge_failures_total", "Failures, by kind", &["kind"])
+            .unwrap();
+    pub static ref WEBSOCKET_CONNECTS: IntCounter = register_int_counter!(
+        "terplounge_websocket_connects_total",
+        "Websocket connections opened"
+    )
+    .unwrap();
+    pub static ref WEBSOCKET_DISCONNECTS: IntCounter = register_int_counter!(
+        "terplounge_websocket_disconnects_total",
+        "Websocket connections closed"
+    )
+    .unwrap();
+    pub static ref AUDIO_BYTES: IntCounter = register_int_counter!(
+        "terplounge_audio_bytes_received_total",
+        "Bytes of audio received over websockets"
+    )
+    .unwrap();
+}
+
+pub fn failure(kind: &str) {
+    FAILURES.with_label_values(&[kind]).inc();
+}
+
+/**
+ * render all metrics in the Prometheus text format. Gauges which are cheap
+ * to compute are updated here rather than kept up to date.
+ */
+pub async fn render(queue: &TranslationQueue) -> E<String> {
+    let sessions = crate::session::get_sessions().await.unwrap_or_default();
+    ACTIVE_SESSIONS.set(sessions.iter().filter(|x| x.valid).count() as i64);
+    QUEUE_LENGTH.set(queue.queued() as i64);
+
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
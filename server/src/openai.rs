@@ -0,0 +1,350 @@
+use log::debug;
+use reqwest::blocking::multipart::{Form, Part};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use std::io::Cursor;
+
+use crate::error::E;
+use crate::translate::{
+    request_timeout, resample, send_cancellable, TranslationRequest, TranslationResponse,
+    Translator, Word,
+};
+
+#[derive(Deserialize, Debug)]
+struct OpenAiWord {
+    word: String,
+    start: f32,
+    end: f32,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiSegment {
+    text: String,
+    start: f32,
+    end: f32,
+    #[serde(default)]
+    avg_logprob: Option<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAiResponse {
+    #[serde(default)]
+    segments: Vec<OpenAiSegment>,
+    #[serde(default)]
+    words: Vec<OpenAiWord>,
+}
+
+/// A translator for servers implementing the OpenAI
+/// `/v1/audio/transcriptions` API, e.g. faster-whisper-server, LocalAI or
+/// the whisper.cpp server.
+pub struct OpenAi {
+    client: Client,
+    url: String,
+    /// where the server lists its models, for the health check.
+    models_url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+impl OpenAi {
+    pub fn new() -> E<Self> {
+        Self::with_url(std::env::var("OPENAI_SERVER")?)
+    }
+
+    pub fn with_url(url: String) -> E<Self> {
+        let client = Client::builder().timeout(request_timeout()).build()?;
+        let model = std::env::var("OPENAI_MODEL").unwrap_or("whisper-1".to_string());
+        let api_key = std::env::var("OPENAI_API_KEY").ok();
+        Ok(Self {
+            client,
+            models_url: models_url(&url)?,
+            url,
+            model,
+            api_key,
+        })
+    }
+}
+
+/// `/v1/models` next to `/v1/audio/transcriptions`, or at the server's root.
+fn models_url(url: &str) -> E<String> {
+    if let Some(i) = url.rfind("/audio/") {
+        return Ok(format!("{}/models", &url[..i]));
+    }
+    Ok(reqwest::Url::parse(url)?.join("/v1/models")?.to_string())
+}
+
+/**
+ * encode 16kHz mono samples as a 16 bit PCM WAV file.
+ */
+fn encode_wav(data: &[f32]) -> E<Vec<u8>> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 16000,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut cursor = Cursor::new(Vec::new());
+    {
+        let mut writer = hound::WavWriter::new(&mut cursor, spec)?;
+        for sample in data {
+            writer.write_sample((sample.clamp(-1f32, 1f32) * i16::MAX as f32) as i16)?;
+        }
+        writer.finalize()?;
+    }
+    Ok(cursor.into_inner())
+}
+
+/**
+ * words are returned for the whole file, so hand each segment those
+ * starting inside it, the last one also taking those starting right at its
+ * end. OpenAI doesn't give per-word probabilities, so use the segment's.
+ */
+fn segment_words(words: &[OpenAiWord], segment: &OpenAiSegment, last: bool) -> Vec<Word> {
+    let confidence = segment.avg_logprob.map(f32::exp);
+    words
+        .iter()
+        .filter(|word| {
+            word.start >= segment.start
+                && (word.start < segment.end || (last && word.start <= segment.end))
+        })
+        .map(|word| Word {
+            word: word.word.trim().to_string(),
+            start: (word.start * 1000f32) as i64,
+            end: (word.end * 1000f32) as i64,
+            confidence,
+        })
+        .collect()
+}
+
+impl Translator for OpenAi {
+    fn translate(&self, translation_request: TranslationRequest) -> E<Vec<TranslationResponse>> {
+        let audio_data = translation_request.payload;
+        if audio_data.is_empty() {
+            return Ok(vec![]);
+        }
+        let data = resample(&audio_data, translation_request.sample_rate as f64);
+        let wav = encode_wav(&data)?;
+
+        let form = Form::new()
+            .part(
+                "file",
+                Part::bytes(wav)
+                    .file_name(format!("{}.wav", translation_request.sequence_number))
+                    .mime_str("audio/wav")?,
+            )
+            .text(
+                "model",
+                translation_request
+                    .model
+                    .clone()
+                    .unwrap_or_else(|| self.model.clone()),
+            )
+            .text("language", translation_request.lang.clone())
+            .text("response_format", "verbose_json")
+            .text("timestamp_granularities[]", "segment")
+            .text("timestamp_granularities[]", "word");
+
+        debug!("Making request for translation to {}", self.url);
+        let mut request = self.client.post(&self.url).multipart(form);
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = send_cancellable(request, &translation_request.cancel)?
+            .error_for_status()?
+            .json::<OpenAiResponse>()?;
+
+        let num_segments = response.segments.len() as i32;
+        let responses = response
+            .segments
+            .iter()
+            .enumerate()
+            .map(|(i, segment)| {
+                let last = i + 1 == response.segments.len();
+                let words = segment_words(&response.words, segment, last);
+                TranslationResponse {
+                    sequence_number: translation_request.sequence_number,
+                    translation: segment.text.clone(),
+                    num_segments,
+                    segment_number: i as i32,
+                    segment_start: (segment.start * 1000f32) as i64,
+                    segment_end: (segment.end * 1000f32) as i64,
+                    uuid: translation_request.uuid.to_string(),
+                    words: if words.is_empty() { None } else { Some(words) },
+                }
+            })
+            .collect();
+        Ok(responses)
+    }
+
+    /**
+     * the transcription endpoint only takes POSTs, so ask for the models.
+     * That takes the API key too, so unlike the transcription endpoints of
+     * other backends anything but a success means it can't be used.
+     */
+    fn health_check(&self) -> E<()> {
+        let mut request = self.client.get(&self.models_url);
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        request.send()?.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session;
+    use crate::testing;
+    use serde_json::json;
+    use warp::http::StatusCode;
+    use warp::hyper::body::Bytes;
+    use warp::Filter;
+
+    /**
+     * answers like an OpenAI-compatible server, checking the form it's sent,
+     * and lists its models only to those sending the key "secret".
+     */
+    fn mock_openai() -> String {
+        let transcriptions = warp::post()
+            .and(warp::path!("v1" / "audio" / "transcriptions"))
+            .and(warp::header::<String>("authorization"))
+            .and(warp::header::<String>("content-type"))
+            .and(warp::body::bytes())
+            .map(|authorization: String, content_type: String, body: Bytes| {
+                assert_eq!(authorization, "Bearer secret");
+                assert!(content_type.starts_with("multipart/form-data"));
+                let form = String::from_utf8_lossy(&body);
+                for field in [
+                    "name=\"model\"\r\n\r\nwhisper-1\r\n",
+                    "name=\"language\"\r\n\r\nde\r\n",
+                    "name=\"response_format\"\r\n\r\nverbose_json\r\n",
+                    "name=\"timestamp_granularities[]\"\r\n\r\nsegment\r\n",
+                    "name=\"timestamp_granularities[]\"\r\n\r\nword\r\n",
+                    "name=\"file\"; filename=\"0.wav\"\r\ncontent-type: audio/wav",
+                ] {
+                    assert!(
+                        form.to_lowercase().contains(&field.to_lowercase()),
+                        "{}",
+                        field
+                    );
+                }
+                assert!(form.contains("RIFF"));
+                warp::reply::json(&json!({
+                    "text": "Guten Tag zusammen",
+                    "segments": [
+                        {"text": " Guten Tag", "start": 0.0, "end": 0.5, "avg_logprob": -0.1},
+                        {"text": " zusammen", "start": 0.5, "end": 1.0}
+                    ],
+                    "words": [
+                        {"word": " Guten", "start": 0.0, "end": 0.2},
+                        {"word": " Tag", "start": 0.2, "end": 0.5},
+                        {"word": " zusammen", "start": 0.5, "end": 1.0}
+                    ]
+                }))
+            });
+        let models = warp::get()
+            .and(warp::path!("v1" / "models"))
+            .and(warp::header::optional::<String>("authorization"))
+            .map(|authorization: Option<String>| {
+                let status = match authorization.as_deref() {
+                    Some("Bearer secret") => StatusCode::OK,
+                    _ => StatusCode::UNAUTHORIZED,
+                };
+                warp::reply::with_status(warp::reply::json(&json!({"data": []})), status)
+            });
+        testing::serve(transcriptions.or(models))
+    }
+
+    fn openai(url: &str, api_key: Option<&str>) -> OpenAi {
+        OpenAi {
+            model: "whisper-1".to_string(),
+            api_key: api_key.map(|x| x.to_string()),
+            ..OpenAi::with_url(url.to_string()).unwrap()
+        }
+    }
+
+    #[test]
+    fn translate_sends_a_form_and_reads_segments_and_words() {
+        let url = format!("{}/v1/audio/transcriptions", mock_openai());
+        let (session_id, _rx) = session::test_session("de");
+        let request = session::get_session_sync(&session_id)
+            .unwrap()
+            .request(0, vec![0.0; 16000]);
+        let responses = openai(&url, Some("secret")).translate(request).unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].translation, " Guten Tag");
+        assert_eq!(responses[1].num_segments, 2);
+        assert_eq!(
+            (responses[1].segment_start, responses[1].segment_end),
+            (500, 1000)
+        );
+        let words = responses[0].words.as_ref().unwrap();
+        let words: Vec<&str> = words.iter().map(|x| x.word.as_str()).collect();
+        assert_eq!(words, vec!["Guten", "Tag"]);
+        let confidence = responses[0].words.as_ref().unwrap()[0].confidence.unwrap();
+        assert!((confidence - (-0.1f32).exp()).abs() < 1e-6);
+        assert_eq!(responses[1].words.as_ref().unwrap()[0].word, "zusammen");
+    }
+
+    #[test]
+    fn only_a_successful_model_listing_is_healthy() {
+        let server = mock_openai();
+        let url = format!("{}/v1/audio/transcriptions", server);
+        assert!(openai(&url, Some("secret")).health_check().is_ok());
+        assert!(openai(&url, None).health_check().is_err());
+        assert!(openai(&url, Some("wrong")).health_check().is_err());
+        let elsewhere = format!("{}/openai/v1/audio/transcriptions", server);
+        assert!(openai(&elsewhere, Some("secret")).health_check().is_err());
+    }
+
+    fn word(word: &str, start: f32, end: f32) -> OpenAiWord {
+        OpenAiWord {
+            word: word.to_string(),
+            start,
+            end,
+        }
+    }
+
+    fn segment(start: f32, end: f32) -> OpenAiSegment {
+        OpenAiSegment {
+            text: String::new(),
+            start,
+            end,
+            avg_logprob: None,
+        }
+    }
+
+    #[test]
+    fn words_are_split_between_segments() {
+        let words = vec![
+            word(" eins", 0.0, 0.4),
+            word(" zwei", 1.0, 1.5),
+            word(" drei", 2.0, 2.0),
+        ];
+        let first = segment_words(&words, &segment(0.0, 1.0), false);
+        let last = segment_words(&words, &segment(1.0, 2.0), true);
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].word, "eins");
+        // a word at the very end of the last segment isn't dropped.
+        let last: Vec<&str> = last.iter().map(|x| x.word.as_str()).collect();
+        assert_eq!(last, vec!["zwei", "drei"]);
+        assert_eq!(first[0].confidence, None);
+    }
+
+    #[test]
+    fn models_are_listed_next_to_the_transcription_endpoint() {
+        assert_eq!(
+            models_url("http://localhost:8000/v1/audio/transcriptions").unwrap(),
+            "http://localhost:8000/v1/models"
+        );
+        assert_eq!(
+            models_url("https://example.com/openai/v1/audio/transcriptions").unwrap(),
+            "https://example.com/openai/v1/models"
+        );
+        assert_eq!(
+            models_url("http://localhost:8080/inference").unwrap(),
+            "http://localhost:8080/v1/models"
+        );
+    }
+}
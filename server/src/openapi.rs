@@ -0,0 +1,481 @@
+// the functions here only carry the documentation of the routes in api.rs
+// and the other modules; the schemas come from the types themselves. The
+// test below checks that every path documented is served.
+#![allow(dead_code)]
+
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::annotations::{Anchor, Annotation, Category, NewAnnotation};
+use crate::api::{SessionList, SessionQuery, SessionStatus};
+use crate::assignments::{
+    Assignment, AssignmentSettings, NewAssignment, NewSubmission, Submission, SubmissionInfo,
+};
+use crate::auth::{Credentials, ErrorReply, LoggedIn, PublicUser, Role, RoleChange};
+use crate::classes::{ClassInfo, Member, NewClass};
+use crate::export::{Segment, Sequence, Transcript};
+use crate::keys::{KeyInfo, NewKey};
+use crate::limits::Quota;
+use crate::messages::{ServerMessage, Status};
+use crate::queue::Priority;
+use crate::session::SessionData;
+use crate::translate::{TranslationResponse, Word};
+use crate::webhooks::{Attempt, Delivery, Event, Payload};
+
+/// start a session. This is a websocket: the client sends audio as binary
+/// messages of 32 bit little-endian float samples, the server replies with
+/// `ServerMessage`s, the first giving the session's uuid.
+#[utoipa::path(
+    get,
+    path = "/chat",
+    tag = "sessions",
+    params(
+        ("lang" = Option<String>, Query, description = "language spoken, default `de`"),
+        ("resource" = Option<String>, Query, description = "the asset being interpreted"),
+        ("priority" = Option<Priority>, Query, description = "default `practice`"),
+        ("model" = Option<String>, Query, description = "model to transcribe with, default the backend's"),
+        ("rate" = Option<u32>, Query, description = "sample rate, default 44100"),
+        ("token" = Option<String>, Query, description = "login token or API key, for clients which can't set headers"),
+    ),
+    responses(
+        (status = 101, description = "switched to the websocket", body = ServerMessage),
+        (status = 401, description = "not logged in", body = ErrorReply),
+        (status = 429, description = "too many connections or out of quota", body = ErrorReply),
+    )
+)]
+fn chat() {}
+
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    tag = "sessions",
+    params(SessionQuery),
+    responses((status = 200, body = SessionList), (status = 401, body = ErrorReply))
+)]
+fn sessions() {}
+
+#[utoipa::path(
+    get,
+    path = "/status/{uuid}",
+    tag = "sessions",
+    params(("uuid" = String, Path, description = "session")),
+    responses((status = 200, body = SessionStatus), (status = 404))
+)]
+fn status() {}
+
+#[utoipa::path(
+    get,
+    path = "/transcript/{uuid}",
+    tag = "sessions",
+    params(("uuid" = String, Path, description = "session")),
+    responses(
+        (status = 200, description = "the transcript so far", body = String, content_type = "text/plain"),
+        (status = 404)
+    )
+)]
+fn transcript() {}
+
+#[utoipa::path(
+    get,
+    path = "/recordings/{uuid}.wav",
+    tag = "sessions",
+    params(("uuid" = String, Path, description = "session")),
+    responses(
+        (status = 200, description = "the recording", content_type = "audio/wav"),
+        (status = 404)
+    )
+)]
+fn recording() {}
+
+/// the transcript with session-relative timing, as text with one line per
+/// sequence, SRT or WebVTT subtitles, or JSON. Missing transcriptions are
+/// shown as `[untranscribed]`, and marked with `gap` in JSON.
+#[utoipa::path(
+    get,
+    path = "/export/{uuid}.{format}",
+    tag = "sessions",
+    params(
+        ("uuid" = String, Path, description = "session"),
+        ("format" = String, Path, description = "`txt`, `srt`, `vtt` or `json`"),
+    ),
+    responses(
+        (status = 200, content(
+            ("application/json" = Transcript),
+            ("text/plain" = String),
+            ("application/x-subrip" = String),
+            ("text/vtt" = String),
+        )),
+        (status = 404)
+    )
+)]
+fn export() {}
+
+/// stop the session once what was sent is transcribed.
+#[utoipa::path(
+    post,
+    path = "/close/{uuid}",
+    tag = "sessions",
+    params(("uuid" = String, Path, description = "session")),
+    responses((status = 200), (status = 404))
+)]
+fn close() {}
+
+/// stop the session now, dropping audio not yet transcribed.
+#[utoipa::path(
+    post,
+    path = "/abort/{uuid}",
+    tag = "sessions",
+    params(("uuid" = String, Path, description = "session")),
+    responses((status = 200), (status = 404))
+)]
+fn abort() {}
+
+#[utoipa::path(get, path = "/quota", tag = "sessions", responses((status = 200, body = Quota)))]
+fn quota() {}
+
+#[utoipa::path(
+    post,
+    path = "/register",
+    tag = "users",
+    request_body = Credentials,
+    responses((status = 200, body = LoggedIn), (status = 400, body = ErrorReply))
+)]
+fn register() {}
+
+#[utoipa::path(
+    post,
+    path = "/login",
+    tag = "users",
+    request_body = Credentials,
+    responses((status = 200, body = LoggedIn), (status = 401, body = ErrorReply))
+)]
+fn login() {}
+
+#[utoipa::path(post, path = "/logout", tag = "users", responses((status = 200)))]
+fn logout() {}
+
+#[utoipa::path(
+    get,
+    path = "/me",
+    tag = "users",
+    responses((status = 200, body = PublicUser), (status = 401, body = ErrorReply))
+)]
+fn me() {}
+
+/// for teachers and admins.
+#[utoipa::path(
+    get,
+    path = "/users",
+    tag = "users",
+    responses((status = 200, body = [PublicUser]), (status = 403, body = ErrorReply))
+)]
+fn users() {}
+
+/// for admins.
+#[utoipa::path(
+    put,
+    path = "/users/{id}/role",
+    tag = "users",
+    params(("id" = Uuid, Path, description = "id")),
+    request_body = RoleChange,
+    responses((status = 200, body = PublicUser), (status = 403, body = ErrorReply), (status = 404))
+)]
+fn set_role() {}
+
+#[utoipa::path(get, path = "/keys", tag = "users", responses((status = 200, body = [KeyInfo])))]
+fn keys() {}
+
+#[utoipa::path(
+    post,
+    path = "/keys",
+    tag = "users",
+    request_body = NewKey,
+    responses((status = 200, description = "the new key, with `key` set", body = KeyInfo))
+)]
+fn create_key() {}
+
+#[utoipa::path(
+    delete,
+    path = "/keys/{id}",
+    tag = "users",
+    params(("id" = Uuid, Path, description = "id")),
+    responses((status = 200), (status = 404))
+)]
+fn delete_key() {}
+
+#[utoipa::path(get, path = "/classes", tag = "classes", responses((status = 200, body = [ClassInfo])))]
+fn classes() {}
+
+#[utoipa::path(
+    post,
+    path = "/classes",
+    tag = "classes",
+    request_body = NewClass,
+    responses((status = 200, body = ClassInfo), (status = 403, body = ErrorReply))
+)]
+fn create_class() {}
+
+#[utoipa::path(
+    get,
+    path = "/classes/{id}",
+    tag = "classes",
+    params(("id" = Uuid, Path, description = "id")),
+    responses((status = 200, body = ClassInfo), (status = 404))
+)]
+fn class() {}
+
+#[utoipa::path(
+    post,
+    path = "/classes/{id}/students",
+    tag = "classes",
+    params(("id" = Uuid, Path, description = "id")),
+    request_body = Member,
+    responses((status = 200, body = ClassInfo), (status = 403, body = ErrorReply))
+)]
+fn add_student() {}
+
+#[utoipa::path(
+    post,
+    path = "/classes/{id}/join",
+    tag = "classes",
+    params(("id" = Uuid, Path, description = "id")),
+    responses((status = 200, body = ClassInfo), (status = 404))
+)]
+fn join_class() {}
+
+#[utoipa::path(
+    delete,
+    path = "/classes/{id}/students/{user_id}",
+    tag = "classes",
+    params(("id" = Uuid, Path, description = "id"), ("user_id" = Uuid, Path, description = "the user's id")),
+    responses((status = 200, body = ClassInfo), (status = 403, body = ErrorReply))
+)]
+fn remove_student() {}
+
+#[utoipa::path(
+    post,
+    path = "/classes/{id}/teachers",
+    tag = "classes",
+    params(("id" = Uuid, Path, description = "id")),
+    request_body = Member,
+    responses((status = 200, body = ClassInfo), (status = 403, body = ErrorReply))
+)]
+fn add_teacher() {}
+
+#[utoipa::path(
+    delete,
+    path = "/classes/{id}/teachers/{user_id}",
+    tag = "classes",
+    params(("id" = Uuid, Path, description = "id"), ("user_id" = Uuid, Path, description = "the user's id")),
+    responses((status = 200, body = ClassInfo), (status = 403, body = ErrorReply))
+)]
+fn remove_teacher() {}
+
+#[utoipa::path(
+    get,
+    path = "/assignments",
+    tag = "assignments",
+    responses((status = 200, body = [Assignment]))
+)]
+fn assignments() {}
+
+#[utoipa::path(
+    post,
+    path = "/assignments",
+    tag = "assignments",
+    request_body = NewAssignment,
+    responses((status = 200, body = Assignment), (status = 400, body = ErrorReply), (status = 403, body = ErrorReply))
+)]
+fn create_assignment() {}
+
+#[utoipa::path(
+    get,
+    path = "/assignments/{id}",
+    tag = "assignments",
+    params(("id" = Uuid, Path, description = "id")),
+    responses((status = 200, body = Assignment), (status = 404))
+)]
+fn assignment() {}
+
+#[utoipa::path(
+    post,
+    path = "/assignments/{id}/submissions",
+    tag = "assignments",
+    params(("id" = Uuid, Path, description = "id")),
+    request_body = NewSubmission,
+    responses((status = 200, body = Submission), (status = 403, body = ErrorReply))
+)]
+fn submit() {}
+
+#[utoipa::path(
+    get,
+    path = "/assignments/{id}/submissions",
+    tag = "assignments",
+    params(("id" = Uuid, Path, description = "id")),
+    responses((status = 200, body = [SubmissionInfo]), (status = 403, body = ErrorReply))
+)]
+fn submissions() {}
+
+#[utoipa::path(
+    get,
+    path = "/annotations/{uuid}",
+    tag = "annotations",
+    params(("uuid" = String, Path, description = "session")),
+    responses((status = 200, body = [Annotation]), (status = 404))
+)]
+fn annotations() {}
+
+#[utoipa::path(
+    post,
+    path = "/annotations/{uuid}",
+    tag = "annotations",
+    params(("uuid" = String, Path, description = "session")),
+    request_body = NewAnnotation,
+    responses((status = 200, body = Annotation), (status = 400, body = ErrorReply), (status = 403, body = ErrorReply))
+)]
+fn annotate() {}
+
+#[utoipa::path(
+    delete,
+    path = "/annotations/{uuid}/{id}",
+    tag = "annotations",
+    params(("uuid" = String, Path, description = "session"), ("id" = Uuid, Path, description = "id")),
+    responses((status = 200), (status = 403, body = ErrorReply), (status = 404))
+)]
+fn delete_annotation() {}
+
+/// for admins. Each delivery is a `Payload` POSTed to one webhook URL.
+#[utoipa::path(
+    get,
+    path = "/webhooks/deliveries",
+    tag = "webhooks",
+    responses((status = 200, body = [Delivery]), (status = 403, body = ErrorReply))
+)]
+fn deliveries() {}
+
+/// for admins.
+#[utoipa::path(
+    post,
+    path = "/webhooks/deliveries/{id}/redeliver",
+    tag = "webhooks",
+    params(("id" = Uuid, Path, description = "id")),
+    responses((status = 202), (status = 403, body = ErrorReply), (status = 404))
+)]
+fn redeliver() {}
+
+/// the ways to authenticate: the login cookie, a token or an API key.
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "cookie",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("terplounge_token"))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))),
+        );
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "terplounge",
+        description = "The HTTP and websocket API. Routes are under /api/v1; the bundled UI \
+            uses the same routes without the prefix."
+    ),
+    servers((url = "/api/v1")),
+    paths(
+        chat, sessions, status, transcript, export, recording, close, abort, quota,
+        register, login, logout, me, users, set_role, keys, create_key, delete_key,
+        classes, create_class, class, add_student, join_class, remove_student, add_teacher,
+        remove_teacher,
+        assignments, create_assignment, assignment, submit, submissions,
+        annotations, annotate, delete_annotation, deliveries, redeliver,
+    ),
+    components(schemas(
+        Anchor, Annotation, Assignment, AssignmentSettings, Attempt, Category, ClassInfo,
+        Credentials, Delivery, ErrorReply, Event, KeyInfo, LoggedIn, Member, NewAnnotation,
+        NewAssignment, NewClass, NewKey, NewSubmission, Payload, Priority, PublicUser, Quota,
+        Role, RoleChange, Segment, Sequence, ServerMessage, SessionData, SessionList,
+        SessionStatus, Status, Submission, SubmissionInfo, Transcript, TranslationResponse, Word,
+    )),
+    modifiers(&Security),
+    security(("cookie" = []), ("bearer" = []), ("api_key" = []))
+)]
+pub struct ApiDoc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, block_on};
+    use utoipa::openapi::PathItemType;
+    use uuid::Uuid;
+    use warp::http::{Method, StatusCode};
+
+    fn method(item: &PathItemType) -> Method {
+        match item {
+            PathItemType::Get => Method::GET,
+            PathItemType::Post => Method::POST,
+            PathItemType::Put => Method::PUT,
+            PathItemType::Delete => Method::DELETE,
+            PathItemType::Options => Method::OPTIONS,
+            PathItemType::Head => Method::HEAD,
+            PathItemType::Patch => Method::PATCH,
+            PathItemType::Trace => Method::TRACE,
+            PathItemType::Connect => Method::CONNECT,
+        }
+    }
+
+    /**
+     * every path documented is served under /api/v1 for its method. The
+     * routes of a session are asked for one that exists, with its recording,
+     * so that only a route missing is not found.
+     */
+    #[test]
+    fn documented_routes_are_served() {
+        let (_, routes) = testing::server(testing::Echo(""));
+        let (session_id, _rx) = crate::session::test_session("de");
+        let uuid = crate::session::get_session_sync(&session_id)
+            .unwrap()
+            .uuid
+            .to_string();
+        let recording = testing::temp_path("recordings").join(&uuid);
+        std::fs::create_dir_all(&recording).unwrap();
+        std::fs::write(recording.join(format!("{}.wav", uuid)), b"").unwrap();
+
+        let paths = ApiDoc::openapi().paths.paths;
+        assert!(!paths.is_empty());
+        for (path, item) in paths {
+            let path = path
+                .replace("{uuid}", &uuid)
+                .replace("{format}", "srt")
+                .replace("{id}", &Uuid::new_v4().to_string())
+                .replace("{user_id}", &Uuid::new_v4().to_string());
+            for operation in item.operations.keys() {
+                let method = method(operation);
+                let response = block_on(
+                    warp::test::request()
+                        .method(method.as_str())
+                        .path(&format!("/api/v1{}", path))
+                        .reply(&routes),
+                );
+                assert!(
+                    ![StatusCode::NOT_FOUND, StatusCode::METHOD_NOT_ALLOWED]
+                        .contains(&response.status()),
+                    "{} {} isn't served: {}",
+                    method,
+                    path,
+                    response.status()
+                );
+            }
+        }
+    }
+}
@@ -1,62 +1,489 @@
-use crate::error::E;
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use lazy_static::lazy_static;
+use crate::error::{Er, E};
+use crate::metrics;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+use uuid::Uuid;
 
-use crate::translate::{TranslationRequest, Translator};
+use crate::translate::{
+    TranslationRequest, TranslationResponse, Translator, SEND_SAMPLE_MINIMUM_TIME_SECONDS,
+};
+
+const RETRY_BACKOFF_MILLISECONDS: u64 = 500;
+/// how many dead letters are kept, older ones are dropped.
+const DEAD_LETTER_LIMIT: usize = 1000;
+
+/// Shared between a session and its requests: once cancelled, queued
+/// requests are dropped and in-flight ones abandoned.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A request which failed on every attempt.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeadLetter {
+    pub session_id: usize,
+    pub uuid: Uuid,
+    pub sequence_number: usize,
+    pub lang: String,
+    pub attempts: usize,
+    pub error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// Priority classes, highest first. Requests of a lower class are only
+/// handed out when no higher class has work waiting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Exam,
+    Practice,
+    Batch,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::Exam, Priority::Practice, Priority::Batch];
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "exam" => Some(Priority::Exam),
+            "practice" => Some(Priority::Practice),
+            "batch" => Some(Priority::Batch),
+            _ => None,
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Pending requests, per session, with the sessions of each priority class
+/// taken in turn.
+#[derive(Default)]
+struct Scheduler {
+    rotations: [VecDeque<usize>; 3],
+    pending: HashMap<usize, VecDeque<TranslationRequest>>,
+}
+
+impl Scheduler {
+    fn push(&mut self, request: TranslationRequest) {
+        let session_id = request.session_id;
+        let rotation = &mut self.rotations[request.priority.index()];
+        let pending = self.pending.entry(session_id).or_default();
+        if pending.is_empty() && !rotation.contains(&session_id) {
+            rotation.push_back(session_id);
+        }
+        pending.push_back(request);
+    }
+
+    /**
+     * take the oldest request of the next session in the highest priority
+     * class with work, and move that session to the back of its rotation.
+     */
+    fn pop(&mut self) -> Option<TranslationRequest> {
+        for priority in Priority::ALL {
+            let rotation = &mut self.rotations[priority.index()];
+            while let Some(session_id) = rotation.pop_front() {
+                let Some(pending) = self.pending.get_mut(&session_id) else {
+                    continue;
+                };
+                let Some(request) = pending.pop_front() else {
+                    self.pending.remove(&session_id);
+                    continue;
+                };
+                if pending.is_empty() {
+                    self.pending.remove(&session_id);
+                } else {
+                    rotation.push_back(session_id);
+                }
+                return Some(request);
+            }
+        }
+        None
+    }
+
+    /// drop all pending requests of a session, returning how many there were.
+    fn remove(&mut self, session_id: usize) -> usize {
+        for rotation in self.rotations.iter_mut() {
+            rotation.retain(|x| *x != session_id);
+        }
+        self.pending
+            .remove(&session_id)
+            .map(|x| x.len())
+            .unwrap_or(0)
+    }
+
+    fn len(&self) -> usize {
+        self.pending.values().map(|x| x.len()).sum()
+    }
+
+    fn depth(&self, session_id: usize) -> usize {
+        self.pending.get(&session_id).map(|x| x.len()).unwrap_or(0)
+    }
+}
 
 #[derive(Clone)]
 pub struct TranslationQueue {
-    sender: Sender<TranslationRequest>,
-    receiver: Option<Receiver<TranslationRequest>>,
+    scheduler: Arc<(Mutex<Scheduler>, Condvar)>,
+    /// the most requests allowed to wait, from `QUEUE_LIMIT`.
+    limit: usize,
+    workers: Arc<AtomicUsize>,
+    /// moving average of the seconds taken per request.
+    average_seconds: Arc<Mutex<f32>>,
+    dead_letters: Arc<RwLock<VecDeque<DeadLetter>>>,
+    /// requests dropped or abandoned because their session was cancelled.
+    cancelled: Arc<AtomicUsize>,
 }
 
-lazy_static! {
-    pub static ref QUEUE: TranslationQueue = TranslationQueue::new().unwrap();
+fn max_attempts() -> usize {
+    std::env::var("TRANSLATE_ATTEMPTS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(3)
+}
+
+/**
+ * translate a request, retrying with exponential backoff. The error of the
+ * last attempt is returned together with the number of attempts made.
+ */
+fn translate_with_retry<T: Translator>(
+    translator: &T,
+    req: &TranslationRequest,
+) -> Result<Vec<TranslationResponse>, (usize, String)> {
+    let attempts = max_attempts().max(1);
+    let mut attempt = 1;
+    loop {
+        match translator.translate(req.clone()) {
+            Ok(responses) => return Ok(responses),
+            Err(e) if attempt >= attempts || req.cancel.is_cancelled() => {
+                return Err((attempt, e.to_string()))
+            }
+            Err(e) => {
+                let backoff = RETRY_BACKOFF_MILLISECONDS * 2u64.pow(attempt as u32 - 1);
+                log::warn!(
+                    "Attempt {} of sequence {} of session {} failed, retrying in {}ms: {}",
+                    attempt,
+                    req.sequence_number,
+                    req.session_id,
+                    backoff,
+                    e
+                );
+                std::thread::sleep(Duration::from_millis(backoff));
+                attempt += 1;
+            }
+        }
+    }
 }
 
 impl TranslationQueue {
     pub fn new() -> E<Self> {
-        let (sender, receiver) = unbounded();
+        let limit = std::env::var("QUEUE_LIMIT")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(1000);
         Ok(Self {
-            sender,
-            receiver: Some(receiver),
+            scheduler: Arc::new((Mutex::new(Scheduler::default()), Condvar::new())),
+            limit,
+            workers: Arc::new(AtomicUsize::new(0)),
+            average_seconds: Arc::new(Mutex::new(SEND_SAMPLE_MINIMUM_TIME_SECONDS as f32)),
+            dead_letters: Arc::new(RwLock::new(VecDeque::new())),
+            cancelled: Arc::new(AtomicUsize::new(0)),
         })
     }
 
+    /// a queue taking at most `limit` requests, for the tests.
+    #[cfg(test)]
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            limit,
+            ..Self::new().unwrap()
+        }
+    }
+
     pub fn enqueue(&self, request: TranslationRequest) -> E<()> {
         log::debug!(
             "Enqueuing request for session with id {}",
             request.session_id
         );
-        self.sender.send(request)?;
+        let (scheduler, available) = &*self.scheduler;
+        let mut scheduler = scheduler.lock().unwrap();
+        if scheduler.len() >= self.limit {
+            return Err(Er::new("transcription queue full".to_string()));
+        }
+        scheduler.push(request);
+        available.notify_one();
         log::debug!("Done");
         Ok(())
     }
 
-    pub async fn queue_process(&self, rx: Receiver<TranslationRequest>) -> E<()> {
-        for translation_request in rx.iter() {
-            self.sender.send(translation_request)?;
+    /// block until a request is available.
+    fn dequeue(&self) -> TranslationRequest {
+        let (scheduler, available) = &*self.scheduler;
+        let mut scheduler = scheduler.lock().unwrap();
+        loop {
+            if let Some(request) = scheduler.pop() {
+                return request;
+            }
+            scheduler = available.wait(scheduler).unwrap();
         }
-        Ok(())
+    }
+
+    pub fn queued(&self) -> usize {
+        self.scheduler.0.lock().unwrap().len()
+    }
+
+    /**
+     * estimate how long a newly queued request would wait for its
+     * transcription, from the queue length, the number of workers and how
+     * long recent requests took.
+     */
+    pub fn estimated_latency(&self) -> Duration {
+        let workers = self.workers.load(Ordering::Relaxed).max(1);
+        let average = *self.average_seconds.lock().unwrap();
+        Duration::from_secs_f32((self.queued() / workers + 1) as f32 * average)
+    }
+
+    fn record_duration(&self, duration: Duration) {
+        let mut average = self.average_seconds.lock().unwrap();
+        *average = 0.8 * *average + 0.2 * duration.as_secs_f32();
+    }
+
+    /// the number of requests of a session waiting for transcription.
+    pub fn depth(&self, session_id: usize) -> usize {
+        self.scheduler.0.lock().unwrap().depth(session_id)
+    }
+
+    /// remove the pending requests of a cancelled session.
+    pub fn cancel(&self, session_id: usize) {
+        let removed = self.scheduler.0.lock().unwrap().remove(session_id);
+        log::debug!(
+            "Cancelled {} queued requests of session {}",
+            removed,
+            session_id
+        );
+        self.count_cancelled(removed);
+    }
+
+    /// count requests dropped or abandoned because their session was cancelled.
+    fn count_cancelled(&self, requests: usize) {
+        self.cancelled.fetch_add(requests, Ordering::Relaxed);
+        crate::metrics::CANCELLED.inc_by(requests as u64);
+    }
+
+    pub fn cancelled(&self) -> usize {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.read().unwrap().iter().cloned().collect()
+    }
+
+    fn add_dead_letter(&self, dead_letter: DeadLetter) {
+        let mut dead_letters = self.dead_letters.write().unwrap();
+        if dead_letters.len() >= DEAD_LETTER_LIMIT {
+            dead_letters.pop_front();
+        }
+        dead_letters.push_back(dead_letter);
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.load(Ordering::Relaxed)
+    }
+
+    /// tell the queue how many more workers are taking requests off it.
+    pub fn add_workers(&self, workers: usize) {
+        self.workers.fetch_add(workers, Ordering::Relaxed);
     }
 
     pub fn subscribe<T: Translator>(&mut self, translator: &T) -> E<()> {
-        while let Some(receiver) = &self.receiver {
-            let req = receiver.recv()?;
-            log::debug!("Queue length: {}", receiver.len());
-            if let Some(session) = crate::session::get_session_sync(&req.session_id)
+        loop {
+            let req = self.dequeue();
+            let span = tracing::info_span!(
+                "chunk",
+                uuid = %req.uuid,
+                session_id = req.session_id,
+                sequence_number = req.sequence_number
+            );
+            let _entered = span.enter();
+            log::debug!("Queue length: {}", self.queued());
+            if req.cancel.is_cancelled() {
+                log::debug!("Skipping cancelled session {}", req.session_id);
+                self.count_cancelled(1);
+            } else if let Some(session) = crate::session::get_session_sync(&req.session_id)
                 && session.valid
             {
-                translator.translate(req)?;
+                let session_id = req.session_id;
+                let sequence_number = req.sequence_number;
+                let started = Instant::now();
+                let translation = translate_with_retry(translator, &req);
+                self.record_duration(started.elapsed());
+                let result = match translation {
+                    _ if req.cancel.is_cancelled() => {
+                        log::debug!(
+                            "Abandoned sequence {} of cancelled session {}",
+                            sequence_number,
+                            session_id
+                        );
+                        self.count_cancelled(1);
+                        Ok(())
+                    }
+                    Ok(responses) => crate::session::process_transcriptions(
+                        session_id,
+                        sequence_number,
+                        &responses,
+                    ),
+                    Err((attempts, error)) => {
+                        log::error!(
+                            "Giving up on sequence {} of session {} after {} attempts: {}",
+                            sequence_number,
+                            session_id,
+                            attempts,
+                            error
+                        );
+                        metrics::failure("dead_letter");
+                        self.add_dead_letter(DeadLetter {
+                            session_id,
+                            uuid: req.uuid,
+                            sequence_number,
+                            lang: req.lang.clone(),
+                            attempts,
+                            error: error.clone(),
+                            failed_at: Utc::now(),
+                        });
+                        crate::session::process_failure(session_id, sequence_number, &error)
+                    }
+                };
+                if let Err(e) = result {
+                    log::warn!("Processing translation failed with error {}", e);
+                    metrics::failure("processing");
+                    crate::session::mutate_session_sync(&session_id, |session| {
+                        session.valid = false
+                    });
+                }
             } else {
                 log::debug!("Skipping no longer valid session {}", req.session_id);
             }
         }
-        log::debug!("Receiver closed.");
-        Ok(())
     }
 }
 
-pub fn get_queue() -> TranslationQueue {
-    (*QUEUE).clone()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(
+        session_id: usize,
+        sequence_number: usize,
+        priority: Priority,
+    ) -> TranslationRequest {
+        TranslationRequest {
+            session_id,
+            uuid: Uuid::new_v4(),
+            sequence_number,
+            payload: vec![],
+            sample_rate: 16000,
+            lang: "de".to_string(),
+            model: None,
+            priority,
+            cancel: Default::default(),
+        }
+    }
+
+    fn popped(scheduler: &mut Scheduler) -> Vec<(usize, usize)> {
+        std::iter::from_fn(|| scheduler.pop())
+            .map(|x| (x.session_id, x.sequence_number))
+            .collect()
+    }
+
+    #[test]
+    fn higher_priority_classes_go_first() {
+        let mut scheduler = Scheduler::default();
+        scheduler.push(request(1, 0, Priority::Batch));
+        scheduler.push(request(2, 0, Priority::Practice));
+        scheduler.push(request(3, 0, Priority::Exam));
+        scheduler.push(request(2, 1, Priority::Practice));
+        assert_eq!(popped(&mut scheduler), vec![(3, 0), (2, 0), (2, 1), (1, 0)]);
+        assert_eq!(scheduler.len(), 0);
+    }
+
+    #[test]
+    fn sessions_of_a_class_take_turns() {
+        let mut scheduler = Scheduler::default();
+        for sequence_number in 0..3 {
+            scheduler.push(request(1, sequence_number, Priority::Practice));
+        }
+        scheduler.push(request(2, 0, Priority::Practice));
+        scheduler.push(request(3, 0, Priority::Practice));
+        scheduler.push(request(3, 1, Priority::Practice));
+        assert_eq!(
+            popped(&mut scheduler),
+            vec![(1, 0), (2, 0), (3, 0), (1, 1), (3, 1), (1, 2)]
+        );
+    }
+
+    #[test]
+    fn removed_sessions_are_skipped() {
+        let mut scheduler = Scheduler::default();
+        scheduler.push(request(1, 0, Priority::Practice));
+        scheduler.push(request(1, 1, Priority::Practice));
+        scheduler.push(request(2, 0, Priority::Practice));
+        assert_eq!(scheduler.remove(1), 2);
+        assert_eq!(scheduler.depth(1), 0);
+        assert_eq!(popped(&mut scheduler), vec![(2, 0)]);
+    }
+
+    #[test]
+    fn a_full_queue_rejects_requests() {
+        let queue = TranslationQueue::with_limit(2);
+        queue.enqueue(request(1, 0, Priority::Practice)).unwrap();
+        queue.enqueue(request(2, 0, Priority::Exam)).unwrap();
+        assert!(queue.enqueue(request(3, 0, Priority::Exam)).is_err());
+        assert_eq!(queue.queued(), 2);
+        assert_eq!(queue.dequeue().session_id, 2);
+        queue.enqueue(request(3, 0, Priority::Exam)).unwrap();
+    }
+
+    #[test]
+    fn cancelled_requests_are_counted() {
+        let queue = TranslationQueue::new().unwrap();
+        queue.enqueue(request(1, 0, Priority::Practice)).unwrap();
+        queue.enqueue(request(1, 1, Priority::Practice)).unwrap();
+        let counted = crate::metrics::CANCELLED.get();
+        queue.cancel(1);
+        assert_eq!(queue.cancelled(), 2);
+        assert!(crate::metrics::CANCELLED.get() >= counted + 2);
+    }
+
+    #[test]
+    fn only_the_latest_dead_letters_are_kept() {
+        let queue = TranslationQueue::new().unwrap();
+        for sequence_number in 0..DEAD_LETTER_LIMIT + 5 {
+            queue.add_dead_letter(DeadLetter {
+                session_id: 1,
+                uuid: Uuid::new_v4(),
+                sequence_number,
+                lang: "de".to_string(),
+                attempts: 3,
+                error: "failed".to_string(),
+                failed_at: Utc::now(),
+            });
+        }
+        let dead_letters = queue.dead_letters();
+        assert_eq!(dead_letters.len(), DEAD_LETTER_LIMIT);
+        assert_eq!(dead_letters[0].sequence_number, 5);
+    }
 }
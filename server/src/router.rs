@@ -0,0 +1,564 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thread_priority::set_current_thread_priority;
+use thread_priority::ThreadPriority::Crossplatform;
+
+use crate::error::{Er, E};
+use crate::metrics;
+use crate::queue::{Priority, TranslationQueue};
+use crate::translate::{request_timeout, TranslationRequest, TranslationResponse, Translator};
+
+const HEALTH_CHECK_SECONDS: u64 = 30;
+/// how long to wait before looking again when every backend is busy.
+const BUSY_WAIT_MILLISECONDS: u64 = 50;
+
+/// a comma separated list from `<NAME>_<SUFFIX>`, `None` if unset or empty.
+fn backend_list(name: &str, suffix: &str) -> Option<Vec<String>> {
+    std::env::var(format!("{}_{}", name.to_uppercase(), suffix))
+        .ok()
+        .filter(|x| !x.is_empty())
+        .map(|x| x.split(',').map(|item| item.trim().to_string()).collect())
+}
+
+/// A translator together with what the router needs to know about it.
+pub struct Backend {
+    pub name: String,
+    translator: Box<dyn Translator + Send + Sync>,
+    /// languages this backend should be used for, `None` meaning all.
+    languages: Option<Vec<String>>,
+    /// models this backend serves, `None` meaning whatever is asked for.
+    models: Option<Vec<String>>,
+    /// priority classes this backend takes requests of, `None` meaning all.
+    priorities: Option<Vec<Priority>>,
+    /// how many requests the backend can handle at once.
+    capacity: usize,
+    in_flight: AtomicUsize,
+    /// false until the backend passes its first health check.
+    healthy: AtomicBool,
+}
+
+impl Backend {
+    pub fn new<T: Translator + Send + Sync + 'static>(
+        name: &str,
+        translator: T,
+        capacity: usize,
+    ) -> Self {
+        let priorities = backend_list(name, "PRIORITIES").map(|priorities| {
+            priorities
+                .iter()
+                .filter_map(|x| {
+                    let priority = Priority::parse(x);
+                    if priority.is_none() {
+                        log::warn!("Ignoring unknown priority {} for backend {}", x, name);
+                    }
+                    priority
+                })
+                .collect()
+        });
+        Self {
+            name: name.to_string(),
+            translator: Box::new(translator),
+            languages: backend_list(name, "LANGUAGES"),
+            models: backend_list(name, "MODELS"),
+            priorities,
+            capacity,
+            in_flight: AtomicUsize::new(0),
+            healthy: AtomicBool::new(false),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn supports(&self, req: &TranslationRequest) -> bool {
+        let language = match &self.languages {
+            Some(languages) => languages.contains(&req.lang),
+            None => true,
+        };
+        let model = match (&self.models, &req.model) {
+            (Some(models), Some(model)) => models.contains(model),
+            _ => true,
+        };
+        let priority = match &self.priorities {
+            Some(priorities) => priorities.contains(&req.priority),
+            None => true,
+        };
+        language && model && priority
+    }
+
+    /// take one of the backend's slots, if it has one free.
+    fn try_acquire(&self) -> Option<Slot<'_>> {
+        self.in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_flight| {
+                (in_flight < self.capacity).then_some(in_flight + 1)
+            })
+            .ok()
+            .map(|_| Slot(self))
+    }
+
+    /// fraction of capacity in use, used to pick the least loaded backend.
+    fn load(&self) -> f32 {
+        self.in_flight.load(Ordering::Relaxed) as f32 / self.capacity.max(1) as f32
+    }
+}
+
+/// A slot taken on a backend, given back when dropped so that a panicking
+/// translator doesn't leave the backend full.
+struct Slot<'a>(&'a Backend);
+
+impl Slot<'_> {
+    fn translate(self, req: TranslationRequest) -> E<Vec<TranslationResponse>> {
+        let backend = self.0;
+        let _span = tracing::info_span!("backend", backend = %backend.name).entered();
+        let audio_seconds = req.payload.len() as f64 / req.sample_rate.max(1) as f64;
+        let started = Instant::now();
+        let result = backend.translator.translate(req);
+        let elapsed = started.elapsed().as_secs_f64();
+        drop(self);
+        if result.is_ok() {
+            metrics::TRANSCRIPTION_SECONDS
+                .with_label_values(&[backend.name.as_str()])
+                .observe(elapsed);
+            if audio_seconds > 0.0 {
+                metrics::REAL_TIME_FACTOR
+                    .with_label_values(&[backend.name.as_str()])
+                    .observe(elapsed / audio_seconds);
+            }
+        } else {
+            metrics::failure("backend");
+        }
+        result
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Picks a backend for each request and fails over to the next one when it
+/// errors or times out. Backends that fail are taken out of rotation until a
+/// health check succeeds.
+pub struct Router {
+    backends: Vec<Backend>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self { backends: vec![] }
+    }
+
+    pub fn add(&mut self, backend: Backend) {
+        log::debug!(
+            "Adding backend {} with capacity {}",
+            backend.name,
+            backend.capacity
+        );
+        self.backends.push(backend);
+    }
+
+    pub fn backends(&self) -> &Vec<Backend> {
+        &self.backends
+    }
+
+    /**
+     * the backends to try for a request, least loaded first: the healthy ones
+     * for the language, model and priority, or the unhealthy ones as a last
+     * resort when no healthy backend handles the request.
+     */
+    fn candidates(&self, req: &TranslationRequest) -> Vec<&Backend> {
+        let (mut healthy, mut unhealthy): (Vec<&Backend>, Vec<&Backend>) = self
+            .backends
+            .iter()
+            .filter(|backend| backend.supports(req))
+            .partition(|backend| backend.is_healthy());
+        let candidates = if healthy.is_empty() {
+            &mut unhealthy
+        } else {
+            &mut healthy
+        };
+        candidates.sort_by(|a, b| a.load().total_cmp(&b.load()));
+        std::mem::take(candidates)
+    }
+
+    pub fn check_health(&self) {
+        for backend in self.backends.iter() {
+            let healthy = match backend.translator.health_check() {
+                Ok(_) => true,
+                Err(e) => {
+                    log::warn!("Health check of backend {} failed: {}", backend.name, e);
+                    false
+                }
+            };
+            if healthy != backend.is_healthy() {
+                log::info!("Backend {} healthy: {}", backend.name, healthy);
+            }
+            backend.healthy.store(healthy, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Translator for Router {
+    /**
+     * try the candidates in turn, skipping those already at capacity. When
+     * every candidate left is busy, wait for one to free up, giving up after
+     * `TRANSLATE_TIMEOUT_SECONDS` or once none of them is healthy any more.
+     * A cancelled request is given up on rather than failed over, and
+     * doesn't count against the backend's health.
+     */
+    fn translate(&self, req: TranslationRequest) -> E<Vec<TranslationResponse>> {
+        let mut candidates = self.candidates(&req);
+        if candidates.is_empty() {
+            return Err(Er::new(format!(
+                "No backend for language {}, model {:?} and priority {:?}",
+                req.lang, req.model, req.priority
+            )));
+        }
+        let only_healthy = candidates[0].is_healthy();
+        let deadline = Instant::now() + request_timeout();
+        let mut last_error = None;
+        while !candidates.is_empty() {
+            if req.cancel.is_cancelled() {
+                return Err(Er::new("request cancelled".to_string()));
+            }
+            let Some((i, slot)) = candidates
+                .iter()
+                .copied()
+                .enumerate()
+                .find_map(|(i, backend)| backend.try_acquire().map(|slot| (i, slot)))
+            else {
+                if only_healthy {
+                    candidates.retain(|backend| backend.is_healthy());
+                }
+                if Instant::now() >= deadline {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(BUSY_WAIT_MILLISECONDS));
+                continue;
+            };
+            let backend = candidates.remove(i);
+            log::debug!(
+                "Routing sequence {} of session {} to {}",
+                req.sequence_number,
+                req.session_id,
+                backend.name
+            );
+            match slot.translate(req.clone()) {
+                Ok(responses) => {
+                    backend.healthy.store(true, Ordering::Relaxed);
+                    return Ok(responses);
+                }
+                Err(e) if req.cancel.is_cancelled() => return Err(e),
+                Err(e) => {
+                    log::warn!("Backend {} failed, trying next: {}", backend.name, e);
+                    backend.healthy.store(false, Ordering::Relaxed);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e),
+            None => Err(Er::new(format!(
+                "No backend free for sequence {} of session {}",
+                req.sequence_number, req.session_id
+            ))),
+        }
+    }
+}
+
+/// check the backends right away, and then every `HEALTH_CHECK_SECONDS`.
+pub fn start_health_checks(router: Arc<Router>) {
+    std::thread::spawn(move || loop {
+        router.check_health();
+        std::thread::sleep(Duration::from_secs(HEALTH_CHECK_SECONDS));
+    });
+}
+
+/**
+ * start `workers` threads at the given priority, each taking requests off
+ * the queue and handing them to the router.
+ */
+pub fn start_pool(
+    router: Arc<Router>,
+    queue: TranslationQueue,
+    workers: usize,
+    priority: u8,
+) -> E<()> {
+    if workers == 0 {
+        return Ok(());
+    }
+    log::debug!("Making thread pool with {} threads.", workers);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(workers)
+        .build()?;
+    queue.add_workers(workers);
+
+    for i in 0..workers {
+        log::debug!("Installing {}", i);
+        let mut queue = queue.clone();
+        let router = router.clone();
+        pool.spawn(move || {
+            set_current_thread_priority(Crossplatform(priority.try_into().unwrap())).unwrap();
+            // supervise the worker: restart it if it errors out or panics.
+            loop {
+                match catch_unwind(AssertUnwindSafe(|| queue.subscribe::<Router>(&router))) {
+                    Ok(Ok(())) => {
+                        log::debug!("Queue closed, exiting worker {}", i);
+                        break;
+                    }
+                    Ok(Err(e)) => log::warn!("Worker {} failed, restarting: {}", i, e),
+                    Err(_) => log::warn!("Worker {} panicked, restarting", i),
+                }
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::Mutex;
+    use uuid::Uuid;
+
+    /// answers with its name, recording how many requests it had at once.
+    struct Fake {
+        name: &'static str,
+        running: AtomicUsize,
+        most_running: Arc<Mutex<usize>>,
+    }
+
+    impl Fake {
+        fn new(name: &'static str) -> Self {
+            Self {
+                name,
+                running: AtomicUsize::new(0),
+                most_running: Arc::new(Mutex::new(0)),
+            }
+        }
+    }
+
+    impl Translator for Fake {
+        fn translate(&self, req: TranslationRequest) -> E<Vec<TranslationResponse>> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            {
+                let mut most_running = self.most_running.lock().unwrap();
+                *most_running = (*most_running).max(running);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(vec![TranslationResponse {
+                sequence_number: req.sequence_number,
+                translation: self.name.to_string(),
+                num_segments: 1,
+                segment_number: 0,
+                segment_start: 0,
+                segment_end: 0,
+                uuid: req.uuid.to_string(),
+                words: None,
+            }])
+        }
+    }
+
+    /// a translator which panics, like whisper.cpp on a poisoned context.
+    struct Panics;
+
+    impl Translator for Panics {
+        fn translate(&self, _req: TranslationRequest) -> E<Vec<TranslationResponse>> {
+            panic!("translator panicked");
+        }
+    }
+
+    /// a translator which fails, cancelling the request first, like
+    /// `send_cancellable` giving up on a closed session.
+    struct CancelsThenFails(Arc<AtomicUsize>);
+
+    impl Translator for CancelsThenFails {
+        fn translate(&self, req: TranslationRequest) -> E<Vec<TranslationResponse>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            req.cancel.cancel();
+            Err(Er::new("request cancelled".to_string()))
+        }
+    }
+
+    /// a translator which always fails, like whisper.cpp failing to run.
+    struct Fails(Arc<AtomicUsize>);
+
+    impl Translator for Fails {
+        fn translate(&self, _req: TranslationRequest) -> E<Vec<TranslationResponse>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(Er::new("failed to run model".to_string()))
+        }
+    }
+
+    fn request(lang: &str, model: Option<&str>, priority: Priority) -> TranslationRequest {
+        TranslationRequest {
+            session_id: 1,
+            uuid: Uuid::new_v4(),
+            sequence_number: 0,
+            payload: vec![],
+            sample_rate: 16000,
+            lang: lang.to_string(),
+            model: model.map(|x| x.to_string()),
+            priority,
+            cancel: Default::default(),
+        }
+    }
+
+    fn routed_to(router: &Router, req: TranslationRequest) -> String {
+        router.translate(req).unwrap()[0].translation.clone()
+    }
+
+    #[test]
+    fn capacity_is_never_exceeded() {
+        let fake = Fake::new("fake");
+        let most_running = fake.most_running.clone();
+        let mut router = Router::new();
+        router.add(Backend::new("capacity_test", fake, 2));
+        let router = Arc::new(router);
+        let threads: Vec<_> = (0..6)
+            .map(|_| {
+                let router = router.clone();
+                std::thread::spawn(move || {
+                    router
+                        .translate(request("de", None, Priority::Practice))
+                        .unwrap()
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*most_running.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn requests_go_to_backends_for_their_model_and_priority() {
+        std::env::set_var("ROUTE_EXAM_PRIORITIES", "exam");
+        std::env::set_var("ROUTE_LARGE_MODELS", "large-v3");
+        let mut router = Router::new();
+        router.add(Backend::new("route_exam", Fake::new("exam"), 1));
+        router.add(Backend::new("route_large", Fake::new("large"), 1));
+
+        let exam = request("de", None, Priority::Exam);
+        assert_eq!(router.candidates(&exam).len(), 2);
+        let practice = request("de", None, Priority::Practice);
+        assert_eq!(routed_to(&router, practice), "large");
+        let large = request("de", Some("large-v3"), Priority::Practice);
+        assert_eq!(routed_to(&router, large), "large");
+        let small = request("de", Some("small"), Priority::Exam);
+        assert_eq!(routed_to(&router, small), "exam");
+        assert!(router
+            .translate(request("de", Some("small"), Priority::Batch))
+            .is_err());
+    }
+
+    #[test]
+    fn unhealthy_backends_are_not_used_while_a_healthy_one_is_busy() {
+        let mut router = Router::new();
+        router.add(Backend::new("busy_test", Fake::new("busy"), 1));
+        router.add(Backend::new("down_test", Fake::new("down"), 1));
+        router.check_health();
+        router.backends[1].healthy.store(false, Ordering::Relaxed);
+        let router = &router;
+        std::thread::scope(|scope| {
+            let slot = router.backends[0].try_acquire().unwrap();
+            let waiting =
+                scope.spawn(|| routed_to(router, request("de", None, Priority::Practice)));
+            std::thread::sleep(Duration::from_millis(200));
+            drop(slot);
+            assert_eq!(waiting.join().unwrap(), "busy");
+        });
+    }
+
+    #[test]
+    fn backends_are_not_ready_before_their_first_health_check() {
+        let mut router = Router::new();
+        router.add(Backend::new("unchecked_test", Fake::new("unchecked"), 1));
+        assert!(!router.backends[0].is_healthy());
+        let router = Arc::new(router);
+        start_health_checks(router.clone());
+        testing::wait_for(|| router.backends[0].is_healthy().then_some(()));
+    }
+
+    #[test]
+    fn a_panicking_backend_gives_its_slot_back() {
+        let mut router = Router::new();
+        router.add(Backend::new("panics_test", Panics, 1));
+        for _ in 0..3 {
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                router.translate(request("de", None, Priority::Practice))
+            }));
+            assert!(result.is_err());
+        }
+        assert_eq!(router.backends[0].in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn cancelled_requests_are_not_failed_over() {
+        let tried = Arc::new(AtomicUsize::new(0));
+        let mut router = Router::new();
+        router.add(Backend::new(
+            "cancel_first",
+            CancelsThenFails(tried.clone()),
+            1,
+        ));
+        router.add(Backend::new(
+            "cancel_second",
+            CancelsThenFails(tried.clone()),
+            1,
+        ));
+        router.check_health();
+        assert!(router
+            .translate(request("de", None, Priority::Practice))
+            .is_err());
+        assert_eq!(tried.load(Ordering::SeqCst), 1);
+        assert!(router.backends.iter().all(|backend| backend.is_healthy()));
+
+        let cancelled = request("de", None, Priority::Practice);
+        cancelled.cancel.cancel();
+        assert!(router.translate(cancelled).is_err());
+        assert_eq!(tried.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn failed_requests_are_retried_and_then_dead_lettered() {
+        let tried = Arc::new(AtomicUsize::new(0));
+        let mut router = Router::new();
+        router.add(Backend::new("fails_test", Fails(tried.clone()), 1));
+        let queue = TranslationQueue::new().unwrap();
+        let (session_id, rx) = crate::session::test_session("de");
+        let request = crate::session::get_session_sync(&session_id)
+            .unwrap()
+            .request(0, vec![0.0; 16000]);
+        queue.enqueue(request).unwrap();
+        let mut worker = queue.clone();
+        std::thread::spawn(move || {
+            let _ = worker.subscribe(&router);
+        });
+
+        // the client is told once the gap is recorded.
+        let failed = testing::wait_for(|| rx.try_recv().ok());
+        assert!(failed.to_str().unwrap().contains("failed_sequence_number"));
+        assert_eq!(tried.load(Ordering::SeqCst), 3);
+        let session = crate::session::get_session_sync(&session_id).unwrap();
+        assert_eq!(session.translations.lock().unwrap().gaps(), vec![0]);
+        let dead_letter = queue
+            .dead_letters()
+            .into_iter()
+            .find(|x| x.session_id == session_id)
+            .unwrap();
+        assert_eq!(dead_letter.attempts, 3);
+        assert_eq!(dead_letter.error, "failed to run model");
+    }
+}
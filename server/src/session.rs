@@ -1,50 +1,82 @@
 use chrono::{DateTime, Utc};
-use crossbeam_channel::{unbounded, Sender};
-use futures_util::{SinkExt, StreamExt, TryFutureExt};
+use crossbeam_channel::{bounded, Sender, TrySendError};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
 use lazy_static::lazy_static;
 use serde::Serialize;
-use serde_json::json;
 use std::collections::HashMap;
 use std::io::Write;
 use std::ops::Deref;
-use std::ops::DerefMut;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::runtime::{Builder, Runtime};
 use tokio::sync::RwLock;
 use tokio::time::timeout;
+use tracing::Instrument;
+use utoipa::ToSchema;
 use uuid::Uuid;
 use warp::ws::{Message, WebSocket};
 
 const RECV_TIMEOUT_SECONDS: u64 = 15;
+const EXPIRE_SECONDS: u64 = 3600;
 
-use crate::error::E;
-use crate::queue::{self};
+use crate::error::{Er, E};
+use crate::limits::{self, Client};
+use crate::messages::{ServerMessage, Status};
+use crate::metrics;
+use crate::queue::{CancellationToken, Priority, TranslationQueue};
 use crate::translate::{self, TranslationResponse, TranslationResponses};
+use crate::webhooks::{self, Event};
 
 pub type Sessions = HashMap<usize, SessionData>;
 
 /// Our global unique user id counter.
 static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
 
-#[derive(Clone, Debug, Serialize)]
+/// Set once the server starts shutting down: no new sessions or audio.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// What a client asks for when starting a session.
+#[derive(Clone, Debug)]
+pub struct SessionOptions {
+    pub language: String,
+    pub priority: Priority,
+    pub sample_rate: u32,
+    pub resource: Option<String>,
+    /// the model to transcribe with, `None` for any.
+    pub model: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
 pub struct SessionData {
     id: usize,
     #[serde(skip_serializing)]
     pub transcription_sender_tx: Option<Sender<Message>>,
-    #[serde(skip_serializing)]
-    pub translator: Sender<translate::TranslationRequest>,
     pub language: String,
+    pub priority: Priority,
+    pub model: Option<String>,
     pub uuid: Uuid,
+    /// the user who started the session, `None` for anonymous sessions.
+    pub owner: Option<Uuid>,
+    /// what the session's audio counts against, see `limits::Client`.
+    #[serde(skip_serializing)]
+    pub quota_key: String,
     pub resource: Option<String>,
     pub sample_rate: u32,
     pub valid: bool,
+    /// set by whoever ends the session first, see `finalize_once`.
+    #[serde(skip_serializing)]
+    pub finalized: bool,
     #[serde(skip_serializing)]
     pub buffer: Vec<f32>,
     pub silence_length: usize,
     pub sequence_number: usize,
     pub last_sequence: Option<usize>,
+    /// the number of samples in each sequence sent, to place its
+    /// transcriptions in the session.
+    #[serde(skip_serializing)]
+    pub sequence_samples: Vec<usize>,
     pub recording: bool,
     #[serde(skip_serializing)]
     pub recording_file: Option<String>,
@@ -52,6 +84,8 @@ pub struct SessionData {
     pub transcript_file: Option<String>,
     #[serde(skip_serializing)]
     pub translations: Arc<Mutex<TranslationResponses>>,
+    #[serde(skip_serializing)]
+    pub cancel: CancellationToken,
     pub updated_at: DateTime<Utc>,
     pub created_at: DateTime<Utc>,
 }
@@ -60,10 +94,8 @@ impl SessionData {
     fn new(
         id: usize,
         transcription_sender_tx: Sender<Message>,
-        translator: Sender<translate::TranslationRequest>,
-        language: String,
-        sample_rate: u32,
-        resource: Option<String>,
+        options: SessionOptions,
+        client: &Client,
     ) -> Self {
         let uuid = Uuid::new_v4();
         let mut recording_file = None;
@@ -78,20 +110,26 @@ impl SessionData {
         Self {
             id,
             transcription_sender_tx: Some(transcription_sender_tx),
-            translator,
-            language,
-            sample_rate,
+            language: options.language,
+            priority: options.priority,
+            model: options.model,
+            sample_rate: options.sample_rate,
             silence_length: 0usize,
             uuid,
-            resource,
+            owner: client.owner,
+            quota_key: client.quota_key(),
+            resource: options.resource,
             recording: recording_file.is_some(),
             recording_file,
             transcript_file,
             valid: true,
+            finalized: false,
             buffer: Vec::new(),
             sequence_number: 0,
             last_sequence: None,
+            sequence_samples: vec![],
             translations: Arc::new(Mutex::new(TranslationResponses::new())),
+            cancel: CancellationToken::default(),
             updated_at: Utc::now(),
             created_at: Utc::now(),
         }
@@ -109,9 +147,7 @@ impl SessionData {
         self.transcription_sender_tx
             .as_ref()
             .ok_or("couldn't find sender")?
-            .send(Message::text(
-                json!({ "uuid": self.uuid.to_string() }).to_string(),
-            ))?;
+            .send(ServerMessage::Started { uuid: self.uuid }.to_message())?;
         Ok(())
     }
 
@@ -121,9 +157,33 @@ impl SessionData {
         Ok(responses.to_string())
     }
 
+    pub fn request(
+        &self,
+        sequence_number: usize,
+        payload: Vec<f32>,
+    ) -> translate::TranslationRequest {
+        translate::TranslationRequest {
+            session_id: self.id,
+            uuid: self.uuid,
+            sequence_number,
+            payload,
+            sample_rate: self.sample_rate,
+            lang: self.language.clone(),
+            model: self.model.clone(),
+            priority: self.priority,
+            cancel: self.cancel.clone(),
+        }
+    }
+
     pub fn finalize_session(&mut self) {
+        if !SYNC_BRIDGE_RUNTIME.block_on(finalize_once(&self.id)) {
+            return;
+        }
         self.record_transcript()
             .expect("error recording transcript");
+        if self.valid {
+            webhooks::session_ended(self, Event::Finished);
+        }
         mutate_session_sync(&self.id, |session| {
             let sender = session.transcription_sender_tx.take();
             drop(sender);
@@ -159,39 +219,101 @@ lazy_static! {
     pub static ref SESSIONS: RwLock<Sessions> = RwLock::new(Sessions::default());
 }
 
-pub fn process_transcription(session_id: usize, response: &TranslationResponse) -> E<()> {
-    let mut session = get_session_sync(&session_id).unwrap();
+/**
+ * the common path for the results of every translator: store the segments
+ * of one sequence in the session, forward them to the websocket, and
+ * finalise the session if this was the last outstanding sequence. A sequence
+ * without any segments is still recorded so that finalisation isn't held up
+ * by silence.
+ */
+pub fn process_transcriptions(
+    session_id: usize,
+    sequence_number: usize,
+    responses: &[TranslationResponse],
+) -> E<()> {
+    let mut session =
+        get_session_sync(&session_id).ok_or(Er::new(format!("no session {}", session_id)))?;
     log::debug!(
-        "Sending {:?} to user\nSessionData is {}, last_sequence = {:?}",
-        response,
-        json!(session).to_string(),
+        "Processing {} segments of sequence {} for session {}, last_sequence = {:?}",
+        responses.len(),
+        sequence_number,
+        session_id,
         session.last_sequence,
     );
-    session
-        .transcription_sender_tx
-        .as_ref()
-        .ok_or("couldn't find sender")?
-        .send(Message::text(json!(response).to_string()))?;
+    {
+        let mut translations = session.translations.lock().unwrap();
+        if responses.is_empty() {
+            translations.add_empty(sequence_number);
+        }
+        for response in responses {
+            translations.add_translation(response)?;
+        }
+    }
+
+    if let Some(sender) = session.transcription_sender_tx.as_ref() {
+        for response in responses {
+            match sender.try_send(ServerMessage::Transcription(response.clone()).to_message()) {
+                Ok(_) => (),
+                Err(TrySendError::Full(_)) => {
+                    // a slow client only misses the live update, the
+                    // transcript still has it.
+                    log::warn!("Websocket channel of session {} full, dropping", session_id);
+                    metrics::failure("websocket_full");
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    // the client went away, but the transcript is still wanted.
+                    log::debug!("Client of session {} disconnected", session_id);
+                    mutate_session_sync(&session_id, |session| {
+                        session.transcription_sender_tx = None;
+                    });
+                    break;
+                }
+            }
+        }
+    }
+
+    finalize_if_done(&mut session);
+    Ok(())
+}
+
+/**
+ * a sequence failed for good: record the gap, tell the client, and finalise
+ * the session if nothing else is outstanding.
+ */
+pub fn process_failure(session_id: usize, sequence_number: usize, error: &str) -> E<()> {
+    let mut session =
+        get_session_sync(&session_id).ok_or(Er::new(format!("no session {}", session_id)))?;
     session
         .translations
         .lock()
         .unwrap()
-        .deref_mut()
-        .add_translation(&response.clone())?;
+        .add_failed(sequence_number);
+    if let Some(sender) = session.transcription_sender_tx.as_ref() {
+        let message = ServerMessage::Failed {
+            uuid: session.uuid,
+            failed_sequence_number: sequence_number,
+            error: error.to_string(),
+        };
+        if let Err(e) = sender.try_send(message.to_message()) {
+            log::debug!("Couldn't send to session {}: {}", session_id, e);
+        }
+    }
+    finalize_if_done(&mut session);
+    Ok(())
+}
 
+fn finalize_if_done(session: &mut SessionData) {
     if let Some(last) = session.last_sequence
         && session.sequence_number >= last
-        && response.segment_number == response.num_segments - 1
         && let Ok(translation_count) = session.get_translation_count()
-        && translation_count >= last + 1
+        && translation_count > last
     {
         log::debug!(
             "Last sequence set and reached. Finalizing session {}.",
-            session_id
+            session.id
         );
         session.finalize_session();
     }
-    Ok(())
 }
 
 pub async fn get_session(id: &usize) -> Option<SessionData> {
@@ -234,6 +356,21 @@ where
     }
 }
 
+/**
+ * whether this is the first call for the session. The last transcription,
+ * a failure and a cancellation can race to end a session; only the first
+ * records it and tells the webhooks.
+ */
+async fn finalize_once(id: &usize) -> bool {
+    let mut first = false;
+    mutate_session(id, |session| {
+        first = !session.finalized;
+        session.finalized = true;
+    })
+    .await;
+    first
+}
+
 pub fn mutate_session_sync<F>(id: &usize, f: F)
 where
     F: FnMut(&mut SessionData),
@@ -243,20 +380,84 @@ where
     });
 }
 
+/// a session as started by a client, for the tests.
+#[cfg(test)]
+pub fn test_session(language: &str) -> (usize, crossbeam_channel::Receiver<Message>) {
+    crate::testing::isolate();
+    let session_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = bounded(channel_limit());
+    let client = Client {
+        owner: None,
+        ip: None,
+    };
+    let options = SessionOptions {
+        language: language.to_string(),
+        priority: Priority::Practice,
+        sample_rate: 16000,
+        resource: None,
+        model: None,
+    };
+    let session = SessionData::new(session_id, tx, options, &client);
+    SYNC_BRIDGE_RUNTIME.block_on(set_session(session_id, session));
+    (session_id, rx)
+}
+
 async fn remove_session(id: &usize) {
     let mut sessions = SESSIONS.write().await;
     sessions.remove(id);
 }
 
-pub async fn user_message(session_id: usize, msg: Message) -> E<()> {
+/**
+ * which limit, if any, a message of `bytes` bytes of audio would break:
+ * the message is too large, the session too long, or the audio would take
+ * the user over their daily quota. The audio is counted against the quota
+ * if it's let through.
+ */
+fn over_limit(session: &SessionData, bytes: usize) -> Option<&'static str> {
+    if bytes > limits::max_message_bytes() {
+        return Some("message_too_large");
+    }
+    let max_session_seconds = limits::max_session_seconds();
+    if max_session_seconds > 0
+        && (Utc::now() - session.created_at).num_seconds() > max_session_seconds as i64
+    {
+        return Some("session_too_long");
+    }
+    let seconds = (bytes / 4) as f64 / session.sample_rate.max(1) as f64;
+    if !limits::charge_audio(&session.quota_key, seconds) {
+        return Some("quota_exceeded");
+    }
+    None
+}
+
+pub async fn user_message(queue: &TranslationQueue, session_id: usize, msg: Message) -> E<()> {
     if !msg.is_binary() {
         // TODO: handle this
         return Ok(());
     }
+    if shutting_down() {
+        return Ok(());
+    }
     let data = msg.into_bytes();
+    metrics::AUDIO_BYTES.inc_by(data.len() as u64);
     if let Some(session) = get_session(&session_id).await
         && let Some(ref _transcription_sender_tx) = session.transcription_sender_tx
     {
+        if let Some(reason) = over_limit(&session, data.len()) {
+            if session.last_sequence.is_none() {
+                log::info!("Closing session {}: {}", session_id, reason);
+                metrics::failure(reason);
+                if let Some(sender) = session.transcription_sender_tx.as_ref() {
+                    let message = ServerMessage::Error {
+                        error: "limit reached".to_string(),
+                        reason: reason.to_string(),
+                    };
+                    let _ = sender.try_send(message.to_message());
+                }
+                mark_session_for_closure(queue, session_id).await;
+            }
+            return Ok(());
+        }
         let mut v: Vec<f32> = data
             .chunks_exact(4)
             .map(|a| f32::from_le_bytes([a[0], a[1], a[2], a[3]]))
@@ -279,36 +480,42 @@ pub async fn user_message(session_id: usize, msg: Message) -> E<()> {
                 0
             };
 
-            log::debug!("Sending to translate, pivot={}", pivot);
+            tracing::debug!(
+                sequence_number = session.sequence_number,
+                pivot,
+                "Sending to translate"
+            );
             let sequence_number = session.sequence_number;
             let payload = session.buffer[..pivot].to_vec();
-            let lang = session.language.clone();
+            metrics::CHUNK_SECONDS.observe(pivot as f64 / session.sample_rate as f64);
             persist_session_data(&session, pivot)?;
-            let result = queue::get_queue().enqueue(translate::TranslationRequest {
-                session_id,
-                sequence_number,
-                payload,
-                lang,
-            });
-            match result {
-                Ok(_) => {
-                    drop(result);
-                    mutate_session(&session_id, |session| {
-                        session.silence_length = silence_length;
-                        session.buffer = session.buffer[pivot..].to_vec();
-                        session.sequence_number += 1;
-                    })
-                    .await;
-                }
-                Err(_) => {
-                    drop(result);
-                    mutate_session(&session_id, |session| {
-                        session.transcription_sender_tx = None;
-                        session.valid = false;
-                    })
-                    .await;
+            let result = queue.enqueue(session.request(sequence_number, payload));
+            // the error isn't Send, so it can't be held across the awaits below.
+            if let Err(e) = result.map_err(|e| e.to_string()) {
+                // the chunk is dropped and shows as a gap, the session
+                // carries on.
+                log::warn!("Couldn't enqueue for session {}: {}", session_id, e);
+                metrics::failure("enqueue");
+                if let Some(sender) = session.transcription_sender_tx.as_ref() {
+                    let message = ServerMessage::Error {
+                        error: e,
+                        reason: "queue_full".to_string(),
+                    };
+                    let _ = sender.try_send(message.to_message());
                 }
+                session
+                    .translations
+                    .lock()
+                    .unwrap()
+                    .add_failed(sequence_number);
             }
+            mutate_session(&session_id, |session| {
+                session.silence_length = silence_length;
+                session.buffer = session.buffer[pivot..].to_vec();
+                session.sequence_samples.push(pivot);
+                session.sequence_number += 1;
+            })
+            .await;
         }
     }
     Ok(())
@@ -316,99 +523,173 @@ pub async fn user_message(session_id: usize, msg: Message) -> E<()> {
 
 pub async fn user_connected(
     ws: WebSocket,
-    translate_tx: Sender<translate::TranslationRequest>,
-    lang: String,
-    sample_rate: u32,
-    resource: Option<String>,
+    queue: TranslationQueue,
+    options: SessionOptions,
+    client: Client,
 ) {
     let session_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
 
     log::debug!("new chat user: {}", session_id);
+    metrics::WEBSOCKET_CONNECTS.inc();
 
-    let (mut user_ws_tx, mut user_ws_rx) = ws.split();
+    let (mut user_ws_tx, user_ws_rx) = ws.split();
 
-    let (transcription_send_tx, transcript_receive_rx) = unbounded();
-    (*WEBSOCKET_SEND_RUNTIME).spawn(async move {
-        for message in transcript_receive_rx.iter() {
-            log::debug!("Sending message");
-            match user_ws_tx.send(message).await {
-                Ok(_) => (),
-                Err(e) => {
-                    log::debug!("websocket send error: {}", e);
-                    break;
+    if !admit(&queue, &mut user_ws_tx).await {
+        log::info!(
+            "Refusing session {}, transcription too far behind",
+            session_id
+        );
+        let _ = user_ws_tx.close().await;
+        metrics::WEBSOCKET_DISCONNECTS.inc();
+        return;
+    }
+
+    let (transcription_send_tx, transcript_receive_rx) = bounded(channel_limit());
+    let mut session = SessionData::new(session_id, transcription_send_tx, options, &client);
+    let span = tracing::info_span!("session", uuid = %session.uuid, session_id);
+
+    (*WEBSOCKET_SEND_RUNTIME).spawn(
+        async move {
+            for message in transcript_receive_rx.iter() {
+                log::debug!("Sending message");
+                match user_ws_tx.send(message).await {
+                    Ok(_) => (),
+                    Err(e) => {
+                        log::debug!("websocket send error: {}", e);
+                        break;
+                    }
                 }
             }
+            log::debug!("Exiting loop");
+            user_ws_tx.close().await.unwrap();
         }
-        log::debug!("Exiting loop");
-        user_ws_tx.close().await.unwrap();
-    });
-
-    let mut session = SessionData::new(
-        session_id,
-        transcription_send_tx,
-        translate_tx,
-        lang,
-        sample_rate,
-        resource,
+        .instrument(span.clone()),
     );
+
     session.send_uuid().unwrap();
     set_session(session_id, session).await;
 
-    loop {
-        if let Ok(Some(result)) =
-            timeout(Duration::from_secs(RECV_TIMEOUT_SECONDS), user_ws_rx.next()).await
-        {
-            let msg = match result {
-                Ok(msg) => msg,
-                Err(e) => {
-                    log::debug!("websocket error(uid={}): {}", session_id, e);
-                    break;
-                }
-            };
+    receive(&queue, session_id, user_ws_rx)
+        .instrument(span)
+        .await;
+}
 
-            let session = get_session(&session_id).await;
-            match session {
-                Some(s) => {
-                    // log::debug!(
-                    //     "Valid = {}, translation_count = {}, last_sequence = {}",
-                    //     s.valid,
-                    //     s.get_translation_count().unwrap_or(0),
-                    //     s.last_sequence.unwrap_or(0),
-                    // );
-                    // if !s.valid && s.get_translation_count().unwrap() == s.last_sequence.unwrap() {
-                    //     break;
-                    // }
-                }
-                None => {
-                    log::warn!("Error getting session {}, bailing", session_id);
-                    break;
-                }
+/// hand the messages of a websocket to `user_message` until the client
+/// goes quiet or disconnects, then mark the session for closure.
+async fn receive(
+    queue: &TranslationQueue,
+    session_id: usize,
+    mut user_ws_rx: SplitStream<WebSocket>,
+) {
+    while let Ok(Some(result)) =
+        timeout(Duration::from_secs(RECV_TIMEOUT_SECONDS), user_ws_rx.next()).await
+    {
+        let msg = match result {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::debug!("websocket error(uid={}): {}", session_id, e);
+                break;
+            }
+        };
+
+        let session = get_session(&session_id).await;
+        match session {
+            Some(_s) => {
+                // log::debug!(
+                //     "Valid = {}, translation_count = {}, last_sequence = {}",
+                //     s.valid,
+                //     s.get_translation_count().unwrap_or(0),
+                //     s.last_sequence.unwrap_or(0),
+                // );
+                // if !s.valid && s.get_translation_count().unwrap() == s.last_sequence.unwrap() {
+                //     break;
+                // }
+            }
+            None => {
+                log::warn!("Error getting session {}, bailing", session_id);
+                break;
             }
-            let _ = user_message(session_id, msg).await;
-        } else {
-            // timed out or error receiving
-            break;
         }
+        let _ = user_message(queue, session_id, msg).await;
     }
     log::debug!("Marking session {} for closure", session_id);
-    mark_session_for_closure(session_id).await;
+    mark_session_for_closure(queue, session_id).await;
     drop(user_ws_rx);
+    metrics::WEBSOCKET_DISCONNECTS.inc();
     log::debug!("Exiting user_connected event loop");
 }
 
-pub async fn mark_session_for_closure_uuid(uuid: String) {
-    if let Some(session_id) = find_session_with_uuid(&uuid).await {
-        mark_session_for_closure(session_id).await;
+fn channel_limit() -> usize {
+    std::env::var("SESSION_CHANNEL_LIMIT")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(256)
+}
+
+fn env_seconds(name: &str, default: u64) -> Duration {
+    Duration::from_secs(
+        std::env::var(name)
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(default),
+    )
+}
+
+/**
+ * admission control: while the estimated transcription latency is over
+ * ADMISSION_LATENCY_SECONDS the client is told it is waitlisted, and if it
+ * doesn't drop within ADMISSION_WAIT_SECONDS it is refused. Returns whether
+ * the session may go ahead.
+ */
+async fn admit(queue: &TranslationQueue, user_ws_tx: &mut SplitSink<WebSocket, Message>) -> bool {
+    let threshold = env_seconds("ADMISSION_LATENCY_SECONDS", 60);
+    let deadline = Instant::now() + env_seconds("ADMISSION_WAIT_SECONDS", 120);
+    loop {
+        if shutting_down() {
+            let message = ServerMessage::Status {
+                status: Status::Refused,
+                reason: "The server is shutting down, please try again later.".to_string(),
+                estimated_latency: None,
+            };
+            let _ = user_ws_tx.send(message.to_message()).await;
+            return false;
+        }
+        let latency = queue.estimated_latency();
+        if latency <= threshold {
+            return true;
+        }
+        let (status, reason) = if Instant::now() >= deadline {
+            (
+                Status::Refused,
+                "The server is too busy to transcribe, please try again later.",
+            )
+        } else {
+            (
+                Status::Waitlisted,
+                "The server is busy, waiting for transcription capacity.",
+            )
+        };
+        let message = ServerMessage::Status {
+            status,
+            reason: reason.to_string(),
+            estimated_latency: Some(latency.as_secs()),
+        };
+        let sent = user_ws_tx.send(message.to_message()).await;
+        if sent.is_err() || status == Status::Refused {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
     }
 }
 
-pub async fn mark_session_for_closure(session_id: usize) {
+/**
+ * close the session once its last sequence is transcribed. If that hasn't
+ * happened within CLOSE_TIMEOUT_SECONDS the outstanding work is cancelled.
+ */
+pub async fn mark_session_for_closure(queue: &TranslationQueue, session_id: usize) {
     let session = get_session(&session_id).await.unwrap();
     if session.sequence_number == 0 {
-        mutate_session(&session_id, |session| {
-            session.transcription_sender_tx = None;
-        })
-        .await;
+        cancel_session(queue, session_id).await;
         return;
     }
     let last_sequence = session.sequence_number - 1;
@@ -421,18 +702,153 @@ pub async fn mark_session_for_closure(session_id: usize) {
         session.last_sequence = Some(last_sequence)
     })
     .await;
+
+    let queue = queue.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(env_seconds("CLOSE_TIMEOUT_SECONDS", 300)).await;
+        if let Some(session) = get_session(&session_id).await
+            && session.valid
+        {
+            log::info!("Session {} didn't finish in time, cancelling", session_id);
+            cancel_session(&queue, session_id).await;
+        }
+    });
 }
 
+/**
+ * abort a session: drop its queued requests, abandon those in flight, and
+ * keep whatever has been transcribed so far. A session that never sent any
+ * audio didn't fail, so the webhooks aren't told about it.
+ */
+pub async fn cancel_session(queue: &TranslationQueue, session_id: usize) {
+    let Some(session) = get_session(&session_id).await else {
+        return;
+    };
+    session.cancel.cancel();
+    queue.cancel(session_id);
+    if session.valid && finalize_once(&session_id).await {
+        if let Err(e) = session.record_transcript() {
+            log::warn!(
+                "Couldn't record transcript of session {}: {}",
+                session_id,
+                e
+            );
+        }
+        if session.sequence_number > 0 {
+            webhooks::session_ended(&session, Event::Failed);
+        }
+    }
+    mutate_session(&session_id, |session| {
+        session.transcription_sender_tx = None;
+        session.valid = false;
+    })
+    .await;
+}
+
+pub fn shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/**
+ * send what is left in a session's buffer for transcription, as there
+ * won't be any more audio to find a silence in.
+ */
+async fn flush_session(queue: &TranslationQueue, session_id: usize) {
+    let Some(session) = get_session(&session_id).await else {
+        return;
+    };
+    if session.buffer.is_empty() {
+        return;
+    }
+    let len = session.buffer.len();
+    if let Err(e) = persist_session_data(&session, len) {
+        log::warn!("Couldn't write recording of session {}: {}", session_id, e);
+    }
+    let request = session.request(session.sequence_number, session.buffer.clone());
+    if queue.enqueue(request).is_ok() {
+        mutate_session(&session_id, |session| {
+            session.buffer.clear();
+            session.sequence_samples.push(len);
+            session.sequence_number += 1;
+        })
+        .await;
+    }
+}
+
+/**
+ * drain the server: refuse new audio, tell clients we are going away, send
+ * the remaining audio of every session and wait up to
+ * SHUTDOWN_TIMEOUT_SECONDS for the transcriptions. Sessions which haven't
+ * finished by then are cancelled, which still writes their transcripts.
+ */
+pub async fn shutdown(queue: &TranslationQueue) {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+    let deadline = Instant::now() + env_seconds("SHUTDOWN_TIMEOUT_SECONDS", 60);
+    let sessions = get_sessions().await.unwrap_or_default();
+    for session in sessions.iter().filter(|x| x.valid) {
+        if let Some(sender) = session.transcription_sender_tx.as_ref() {
+            let message = ServerMessage::Status {
+                status: Status::ShuttingDown,
+                reason: "The server is shutting down, finishing the transcription.".to_string(),
+                estimated_latency: None,
+            };
+            let _ = sender.try_send(message.to_message());
+        }
+        flush_session(queue, session.id).await;
+        mark_session_for_closure(queue, session.id).await;
+    }
+
+    loop {
+        let remaining: Vec<usize> = get_sessions()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .filter(|x| x.valid)
+            .map(|x| x.id)
+            .collect();
+        if remaining.is_empty() {
+            log::info!("All sessions finished");
+            break;
+        }
+        if Instant::now() >= deadline {
+            log::warn!("{} sessions didn't finish in time", remaining.len());
+            for session_id in remaining {
+                cancel_session(queue, session_id).await;
+            }
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// forget the sessions which haven't been updated for a day.
 pub async fn expire_sessions() -> E<()> {
     let now = Utc::now().timestamp();
-    for (session_id, session) in (*SESSIONS).read().await.iter() {
-        if now - session.updated_at.timestamp() > 86400 {
-            remove_session(session_id).await;
-        }
+    let expired: Vec<usize> = (*SESSIONS)
+        .read()
+        .await
+        .iter()
+        .filter(|(_, session)| now - session.updated_at.timestamp() > 86400)
+        .map(|(session_id, _)| *session_id)
+        .collect();
+    for session_id in expired.iter() {
+        remove_session(session_id).await;
     }
     Ok(())
 }
 
+/// expire sessions every `EXPIRE_SECONDS`, so they don't pile up in memory.
+pub fn start_expiry() {
+    tokio::spawn(async {
+        loop {
+            tokio::time::sleep(Duration::from_secs(EXPIRE_SECONDS)).await;
+            if let Err(e) = expire_sessions().await {
+                log::error!("Couldn't expire sessions: {}", e);
+            }
+        }
+    });
+}
+
 fn persist_session_data(session: &SessionData, pivot: usize) -> E<()> {
     if let Some(filename) = &session.recording_file {
         let spec = hound::WavSpec {
@@ -454,3 +870,81 @@ fn persist_session_data(session: &SessionData, pivot: usize) -> E<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_not_updated_for_a_day_expire() {
+        let (stale, _stale_rx) = test_session("de");
+        let (fresh, _fresh_rx) = test_session("de");
+        let mut session = get_session_sync(&stale).unwrap();
+        session.updated_at = Utc::now() - chrono::Duration::hours(25);
+        SYNC_BRIDGE_RUNTIME.block_on(async {
+            set_session(stale, session).await;
+            expire_sessions().await.unwrap();
+        });
+        assert!(get_session_sync(&stale).is_none());
+        assert!(get_session_sync(&fresh).is_some());
+    }
+
+    #[test]
+    fn sessions_are_finalized_once() {
+        let (session_id, _rx) = test_session("de");
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                std::thread::spawn(move || SYNC_BRIDGE_RUNTIME.block_on(finalize_once(&session_id)))
+            })
+            .collect();
+        let finalized = threads
+            .into_iter()
+            .map(|x| x.join().unwrap())
+            .filter(|x| *x)
+            .count();
+        assert_eq!(finalized, 1);
+    }
+
+    fn silence(samples: usize) -> Message {
+        let audio: Vec<u8> = vec![0f32; samples]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        Message::binary(audio)
+    }
+
+    #[test]
+    fn chunks_the_queue_cannot_take_are_gaps() {
+        let (session_id, _rx) = test_session("de");
+        mutate_session_sync(&session_id, |session| session.sample_rate = 1000);
+        let full = TranslationQueue::with_limit(0);
+        let queue = TranslationQueue::new().unwrap();
+        // a chunk is cut from what was buffered before a message, at the
+        // silence 15.1s in.
+        crate::testing::block_on(async {
+            user_message(&full, session_id, silence(15_300))
+                .await
+                .unwrap();
+            user_message(&full, session_id, silence(100)).await.unwrap();
+            user_message(&queue, session_id, silence(15_000))
+                .await
+                .unwrap();
+            user_message(&queue, session_id, silence(100))
+                .await
+                .unwrap();
+        });
+        assert_eq!(queue.queued(), 1);
+        let session = get_session_sync(&session_id).unwrap();
+        assert!(session.valid);
+        assert_eq!(session.sequence_number, 2);
+        assert_eq!(session.sequence_samples, vec![15_100, 15_100]);
+        assert_eq!(session.translations.lock().unwrap().gaps(), vec![0]);
+
+        session.translations.lock().unwrap().add_empty(1);
+        let transcript = crate::export::transcript(&session);
+        assert_eq!(transcript.sequences[0].start_ms, Some(0));
+        assert!(transcript.sequences[0].gap);
+        assert_eq!(transcript.sequences[1].start_ms, Some(15_100));
+        assert_eq!(transcript.sequences[1].end_ms, Some(30_200));
+    }
+}
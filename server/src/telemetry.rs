@@ -0,0 +1,56 @@
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::error::E;
+
+/**
+ * set up logging and tracing. `RUST_LOG` filters as before, `LOG_FORMAT=json`
+ * switches to one JSON object per line, and if `OTEL_EXPORTER_OTLP_ENDPOINT`
+ * is set (e.g. http://localhost:4317 for a local collector) spans are
+ * exported over OTLP too. Records from the `log` crate end up in the
+ * current span.
+ */
+pub fn init() -> E<()> {
+    let fmt = match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+        _ => tracing_subscriber::fmt::layer().boxed(),
+    };
+
+    let otlp = match std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", "terplounge"),
+                ])))
+                .install_batch(runtime::Tokio)?;
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        Err(_) => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(fmt)
+        .with(otlp)
+        .try_init()?;
+    Ok(())
+}
+
+/// flush spans which haven't been exported yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,181 @@
+//! Helpers for the tests: mock servers for the services we talk to, and
+//! server instances of their own with a fake translator.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Once};
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::{Role, User};
+use crate::error::E;
+use crate::queue::TranslationQueue;
+use crate::router::{Backend, Router};
+use crate::translate::{TranslationRequest, TranslationResponse, Translator};
+
+/// where the stores keep their files, by the variable naming each file.
+const STORE_FILES: [(&str, &str); 7] = [
+    ("USERS_FILE", "users.json"),
+    ("API_KEYS_FILE", "api_keys.json"),
+    ("CLASSES_FILE", "classes.json"),
+    ("ASSIGNMENTS_FILE", "assignments.json"),
+    ("ANNOTATIONS_FILE", "annotations.json"),
+    ("LTI_LINKS_FILE", "lti_links.json"),
+    ("WEBHOOK_LOG_FILE", "webhook_deliveries.jsonl"),
+];
+
+/// a file in the directory this test run keeps its files in.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir()
+        .join(format!("terplounge-test-{}", std::process::id()))
+        .join(name)
+}
+
+/**
+ * keep the stores and recordings of this test run in a directory of their
+ * own rather than the working directory, and allow anonymous sessions.
+ * Called by the other helpers; tests using a store directly call it first.
+ */
+pub fn isolate() {
+    static ISOLATE: Once = Once::new();
+    ISOLATE.call_once(|| {
+        std::fs::create_dir_all(temp_path("")).unwrap();
+        for (name, file) in STORE_FILES {
+            std::env::set_var(name, temp_path(file));
+        }
+        std::env::set_var("ALLOW_ANONYMOUS", "true");
+        std::env::set_var("RECORDINGS_DIR", temp_path("recordings"));
+    });
+}
+
+/// register a user with the given role, returning them and a token to send
+/// as `Authorization: Bearer`.
+pub async fn user(role: Role) -> (User, String) {
+    isolate();
+    let name = format!("user-{}", uuid::Uuid::new_v4());
+    let user = crate::auth::register(name.clone(), "password".to_string())
+        .await
+        .unwrap();
+    let user = crate::auth::set_role(&user.id, role).await.unwrap();
+    let response = warp::test::request()
+        .method("POST")
+        .path("/login")
+        .json(&serde_json::json!({"username": name, "password": "password"}))
+        .reply(&crate::auth::routes())
+        .await;
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    (user, body["token"].as_str().unwrap().to_string())
+}
+
+/// send a request to `routes` as the user `token` is for, returning the
+/// status it is answered with.
+pub async fn status<F>(
+    routes: &F,
+    method: &str,
+    path: &str,
+    token: &str,
+    body: Option<serde_json::Value>,
+) -> StatusCode
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply + Send,
+{
+    let mut request = warp::test::request()
+        .method(method)
+        .path(path)
+        .header("authorization", format!("Bearer {}", token));
+    if let Some(body) = body {
+        request = request.json(&body);
+    }
+    request
+        .reply(&routes.clone().recover(crate::auth::handle_rejection))
+        .await
+        .status()
+}
+
+/// A translator answering every request with the same text.
+pub struct Echo(pub &'static str);
+
+impl Translator for Echo {
+    fn translate(&self, req: TranslationRequest) -> E<Vec<TranslationResponse>> {
+        Ok(vec![TranslationResponse {
+            sequence_number: req.sequence_number,
+            translation: self.0.to_string(),
+            num_segments: 1,
+            segment_number: 0,
+            segment_start: 0,
+            segment_end: 1000,
+            uuid: req.uuid.to_string(),
+            words: None,
+        }])
+    }
+}
+
+/**
+ * a server instance of its own: a queue, worked by one thread handing its
+ * requests to `translator`, and the API routes for it.
+ */
+pub fn server<T: Translator + Send + Sync + 'static>(
+    translator: T,
+) -> (TranslationQueue, BoxedFilter<(Box<dyn Reply>,)>) {
+    isolate();
+    let queue = TranslationQueue::new().unwrap();
+    let mut router = Router::new();
+    router.add(Backend::new("test", translator, 1));
+    let router = Arc::new(router);
+    queue.add_workers(1);
+    let mut worker = queue.clone();
+    let worker_router = router.clone();
+    std::thread::spawn(move || {
+        let _ = worker.subscribe(&*worker_router);
+    });
+    let routes = crate::api::routes(queue.clone(), router)
+        .map(|reply| Box::new(reply) as Box<dyn Reply>)
+        .boxed();
+    (queue, routes)
+}
+
+/**
+ * serve `routes` on a free local port from a thread of its own, for as long
+ * as the tests run. Returns the server's base url.
+ */
+pub fn serve<F, R>(routes: F) -> String
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let (address, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+            tx.send(address).unwrap();
+            server.await;
+        });
+    });
+    format!("http://{}", rx.recv().unwrap())
+}
+
+/// poll `f` until it returns something, failing the test after 10 seconds.
+pub fn wait_for<T>(mut f: impl FnMut() -> Option<T>) -> T {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    loop {
+        if let Some(x) = f() {
+            return x;
+        }
+        assert!(std::time::Instant::now() < deadline, "timed out waiting");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+}
+
+/// run a future to completion, for testing async code from a plain test.
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
@@ -2,35 +2,152 @@ use rubato::{
     Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
 };
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
 
-use crate::error::E;
+use crate::error::{Er, E};
+use crate::queue::{CancellationToken, Priority};
 
+/// A speech-to-text backend. Implementations only transcribe, storing
+/// and forwarding the results is left to `session::process_transcriptions`.
 pub trait Translator {
-    fn translate(&self, req: TranslationRequest) -> E<()>;
+    fn translate(&self, req: TranslationRequest) -> E<Vec<TranslationResponse>>;
+
+    /// check whether the backend can currently take requests.
+    fn health_check(&self) -> E<()> {
+        Ok(())
+    }
+}
+
+/// How long to wait for a remote backend before failing over.
+pub fn request_timeout() -> std::time::Duration {
+    let seconds = std::env::var("TRANSLATE_TIMEOUT_SECONDS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(60);
+    std::time::Duration::from_secs(seconds)
+}
+
+/// a remote backend is up if `url` answers with anything short of a server error.
+pub fn check_health(client: &reqwest::blocking::Client, url: &str) -> E<()> {
+    let res = client.get(url).send()?;
+    if res.status().is_server_error() {
+        return Err(Er::new(format!("{} returned {}", url, res.status())));
+    }
+    Ok(())
+}
+
+/**
+ * send a request to a remote backend, giving up as soon as the request's
+ * session is cancelled. The blocking client can't abort a request, so it
+ * is sent from its own thread and its answer discarded.
+ */
+pub fn send_cancellable(
+    request: reqwest::blocking::RequestBuilder,
+    cancel: &CancellationToken,
+) -> E<reqwest::blocking::Response> {
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    let span = tracing::Span::current();
+    std::thread::spawn(move || {
+        let _entered = span.enter();
+        let _ = tx.send(request.send().map_err(|e| e.to_string()));
+    });
+    loop {
+        match rx.recv_timeout(std::time::Duration::from_millis(100)) {
+            Ok(Ok(response)) => return Ok(response),
+            Ok(Err(e)) => return Err(Er::new(e)),
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) if cancel.is_cancelled() => {
+                return Err(Er::new("request cancelled".to_string()))
+            }
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// The number of concurrent requests a remote backend is sent, from
+/// `<NAME>_CAPACITY`, default 1.
+pub fn remote_capacity(name: &str) -> usize {
+    std::env::var(format!("{}_CAPACITY", name.to_uppercase()))
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(1)
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TranslationRequest {
     pub session_id: usize,
+    pub uuid: Uuid,
     pub sequence_number: usize,
     pub payload: Vec<f32>,
+    pub sample_rate: u32,
     pub lang: String,
+    /// the model asked for, `None` for the backend's default.
+    pub model: Option<String>,
+    pub priority: Priority,
+    #[serde(skip)]
+    pub cancel: CancellationToken,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, ToSchema)]
 pub struct TranslationResponse {
     pub sequence_number: usize,
     pub translation: String,
     pub num_segments: i32,
     pub segment_number: i32,
+    /// milliseconds from the start of the sequence.
     pub segment_start: i64,
     pub segment_end: i64,
     pub uuid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub words: Option<Vec<Word>>,
 }
 
-impl ToString for TranslationResponse {
-    fn to_string(&self) -> String {
-        self.translation.clone()
+/// A single transcribed word. Times are in milliseconds relative to the
+/// start of the chunk, confidence is between 0 and 1, and left out where
+/// the backend doesn't give one.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Word {
+    pub word: String,
+    pub start: i64,
+    pub end: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f32>,
+}
+
+/**
+ * merge sub-word tokens of (text, start, end, probability) into words. A
+ * token starting with a space starts a new word, the confidence of a word
+ * is the lowest probability of its tokens.
+ */
+pub fn merge_tokens(tokens: &[(String, i64, i64, f32)]) -> Vec<Word> {
+    let mut words: Vec<Word> = vec![];
+    for (text, start, end, probability) in tokens {
+        match words.last_mut() {
+            Some(word) if !text.starts_with(' ') => {
+                word.word.push_str(text);
+                word.end = *end;
+                word.confidence = word.confidence.map(|x| x.min(*probability));
+            }
+            _ => {
+                if text.trim().is_empty() {
+                    continue;
+                }
+                words.push(Word {
+                    word: text.trim_start().to_string(),
+                    start: *start,
+                    end: *end,
+                    confidence: Some(*probability),
+                });
+            }
+        }
+    }
+    words
+}
+
+impl std::fmt::Display for TranslationResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.translation)
     }
 }
 
@@ -43,7 +160,7 @@ impl TranslationResponses {
     }
 
     pub fn add_translation(&mut self, response: &TranslationResponse) -> E<()> {
-        let sequence_number = response.sequence_number as usize;
+        let sequence_number = response.sequence_number;
         let segment_number = response.segment_number as usize;
         if self.0.len() < sequence_number + 1 {
             log::debug!(
@@ -66,14 +183,67 @@ impl TranslationResponses {
         Ok(())
     }
 
+    /// record that a sequence was transcribed but contained no speech, so
+    /// it isn't mistaken for a missing one.
+    pub fn add_empty(&mut self, sequence_number: usize) {
+        if self.0.len() < sequence_number + 1 {
+            self.0.resize(sequence_number + 1, None);
+        }
+        if self.0[sequence_number].is_none() {
+            self.0[sequence_number] = Some(vec![]);
+        }
+    }
+
+    /// record that a sequence couldn't be transcribed. It is stored as a
+    /// single missing segment, so it counts as done but shows as a gap.
+    pub fn add_failed(&mut self, sequence_number: usize) {
+        if self.0.len() < sequence_number + 1 {
+            self.0.resize(sequence_number + 1, None);
+        }
+        self.0[sequence_number] = Some(vec![None]);
+    }
+
+    /// the transcription of one segment of a sequence, if there is one.
+    pub fn segment(
+        &self,
+        sequence_number: usize,
+        segment_number: usize,
+    ) -> Option<&TranslationResponse> {
+        self.0
+            .get(sequence_number)?
+            .as_ref()?
+            .get(segment_number)?
+            .as_ref()
+    }
+
+    /// the sequences with no transcription or segments missing, e.g.
+    /// because transcribing them failed.
+    pub fn gaps(&self) -> Vec<usize> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, segments)| match segments {
+                Some(segments) => segments.iter().any(|x| x.is_none()),
+                None => true,
+            })
+            .map(|(sequence_number, _)| sequence_number)
+            .collect()
+    }
+
+    /// the segments of each sequence: `None` for a sequence not transcribed
+    /// (yet), `None` segments for those missing, no segments for silence.
+    pub fn sequences(&self) -> &[Option<Vec<Option<TranslationResponse>>>] {
+        &self.0
+    }
+
     pub fn translation_count(&self) -> E<usize> {
         let count = self.0.iter().filter(|x| !x.is_none()).count();
         Ok(count)
     }
 }
 
-impl ToString for TranslationResponses {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for TranslationResponses {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let mut result = String::new();
 
         for responses in self.0.iter() {
@@ -89,7 +259,7 @@ impl ToString for TranslationResponses {
                 None => result.push_str(" .... "),
             }
         }
-        result
+        f.write_str(&result)
     }
 }
 
@@ -160,3 +330,37 @@ pub fn find_silence(buffer: &[f32], sample_rate: u32) -> Option<usize> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_tokens_joins_sub_words() {
+        let tokens = vec![
+            (" Hel".to_string(), 0, 100, 0.9),
+            ("lo".to_string(), 100, 200, 0.5),
+            (" world".to_string(), 250, 400, 0.8),
+        ];
+        let words = merge_tokens(&tokens);
+        assert_eq!(words.len(), 2);
+        assert_eq!(words[0].word, "Hello");
+        assert_eq!((words[0].start, words[0].end), (0, 200));
+        assert_eq!(words[0].confidence, Some(0.5));
+        assert_eq!(words[1].word, "world");
+    }
+
+    #[test]
+    fn missing_confidence_is_not_serialized() {
+        let word = Word {
+            word: "hallo".to_string(),
+            start: 0,
+            end: 10,
+            confidence: None,
+        };
+        let json = serde_json::to_value(&word).unwrap();
+        assert!(json.get("confidence").is_none());
+        let word: Word = serde_json::from_value(json).unwrap();
+        assert_eq!(word.confidence, None);
+    }
+}
@@ -0,0 +1,401 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::io::Write;
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::RwLock;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::{require_user, Forbidden, User};
+use crate::error::E;
+use crate::session::SessionData;
+
+/// how many deliveries the log keeps.
+const LOG_SIZE: usize = 1000;
+/// how many lines the log file may grow to before it's rewritten.
+const LOG_LINES: usize = 2 * LOG_SIZE;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Event {
+    /// everything sent was transcribed.
+    #[serde(rename = "session.finished")]
+    Finished,
+    /// the session was aborted or cancelled before it finished.
+    #[serde(rename = "session.failed")]
+    Failed,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Finished => "session.finished",
+            Event::Failed => "session.failed",
+        }
+    }
+}
+
+/// What is POSTed to the webhook URLs when a session ends.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Payload {
+    pub event: Event,
+    pub session: Uuid,
+    pub owner: Option<Uuid>,
+    /// the asset (resource) the session was recorded for.
+    pub asset: Option<String>,
+    /// the language spoken.
+    pub language: String,
+    pub transcript: String,
+    /// against the asset's text in `language`, from 0 to 1, see
+    /// `compare::score`.
+    pub score: Option<f32>,
+    /// sequences with no transcription, e.g. because it failed.
+    pub gaps: Vec<usize>,
+    pub created_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+}
+
+/// One try at delivering a payload.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Attempt {
+    pub at: DateTime<Utc>,
+    /// the HTTP status of the reply, if there was one.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// A payload for one URL, and how delivering it went.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct Delivery {
+    pub id: Uuid,
+    pub url: String,
+    pub payload: Payload,
+    pub attempts: Vec<Attempt>,
+    pub delivered: bool,
+}
+
+lazy_static! {
+    static ref WEBHOOK_RUNTIME: Runtime = Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("webhook-runtime")
+        .enable_all()
+        .build()
+        .unwrap();
+    static ref DELIVERIES: RwLock<Log> = RwLock::new(load_deliveries());
+}
+
+/// The deliveries kept, and how many lines the log file has.
+#[derive(Default)]
+struct Log {
+    deliveries: Vec<Delivery>,
+    lines: usize,
+}
+
+impl Log {
+    /// add a delivery, or update it, dropping the oldest if it's full.
+    fn update(&mut self, delivery: &Delivery) {
+        match self.deliveries.iter_mut().find(|x| x.id == delivery.id) {
+            Some(x) => *x = delivery.clone(),
+            None => self.deliveries.push(delivery.clone()),
+        }
+        let full = self.deliveries.len().saturating_sub(LOG_SIZE);
+        self.deliveries.drain(..full);
+    }
+}
+
+/// where to send events, from the comma separated `WEBHOOK_URLS`.
+fn urls() -> Vec<String> {
+    std::env::var("WEBHOOK_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+fn attempts() -> u32 {
+    std::env::var("WEBHOOK_ATTEMPTS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(5)
+        .max(1)
+}
+
+fn log_file() -> String {
+    std::env::var("WEBHOOK_LOG_FILE").unwrap_or("webhook_deliveries.jsonl".to_string())
+}
+
+/// the log file has a line for each attempt, the last for a delivery wins.
+fn load_deliveries() -> Log {
+    let mut log = Log::default();
+    let Ok(content) = std::fs::read_to_string(log_file()) else {
+        return log;
+    };
+    for line in content.lines() {
+        log.lines += 1;
+        match serde_json::from_str(line) {
+            Ok(delivery) => log.update(&delivery),
+            Err(e) => log::error!("Couldn't parse a line of {}: {}", log_file(), e),
+        }
+    }
+    log
+}
+
+fn append_delivery(delivery: &Delivery) -> E<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file())?;
+    writeln!(file, "{}", serde_json::to_string(delivery)?)?;
+    Ok(())
+}
+
+/// write just the deliveries kept, so the file doesn't grow forever.
+fn save_deliveries(deliveries: &[Delivery]) -> E<()> {
+    let mut content = String::new();
+    for delivery in deliveries {
+        content.push_str(&serde_json::to_string(delivery)?);
+        content.push('\n');
+    }
+    std::fs::write(log_file(), content)?;
+    Ok(())
+}
+
+/**
+ * add a delivery to the log, or update it, by appending it to the log file.
+ * Once the file has `LOG_LINES` lines it's rewritten with just the
+ * deliveries kept.
+ */
+async fn record(delivery: &Delivery) {
+    let mut log = DELIVERIES.write().await;
+    log.update(delivery);
+    let saved = if log.lines < LOG_LINES {
+        log.lines += 1;
+        append_delivery(delivery)
+    } else {
+        log.lines = log.deliveries.len();
+        save_deliveries(&log.deliveries)
+    };
+    if let Err(e) = saved {
+        log::error!("Couldn't save webhook deliveries: {}", e);
+    }
+}
+
+/**
+ * the signature of a payload: the hex HMAC-SHA256, keyed with
+ * `WEBHOOK_SECRET`, of the timestamp, a dot and the body.
+ */
+fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+async fn post(delivery: &Delivery, body: &str) -> Result<StatusCode, reqwest::Error> {
+    let timestamp = Utc::now().timestamp();
+    let mut request = reqwest::Client::new()
+        .post(&delivery.url)
+        .timeout(TIMEOUT)
+        .header("content-type", "application/json")
+        .header("x-terplounge-event", delivery.payload.event.name())
+        .header("x-terplounge-delivery", delivery.id.to_string())
+        .header("x-terplounge-timestamp", timestamp.to_string());
+    if let Ok(secret) = std::env::var("WEBHOOK_SECRET") {
+        request = request.header(
+            "x-terplounge-signature",
+            format!("sha256={}", sign(&secret, timestamp, body)),
+        );
+    }
+    let response = request.body(body.to_string()).send().await?;
+    Ok(StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY))
+}
+
+/**
+ * post a delivery until it gets a 2xx reply, up to `WEBHOOK_ATTEMPTS`
+ * (default 5) times, waiting 1, 2, 4... minutes in between. Every attempt
+ * goes in the log.
+ */
+async fn deliver(mut delivery: Delivery) {
+    let body = match serde_json::to_string(&delivery.payload) {
+        Ok(body) => body,
+        Err(e) => {
+            log::error!("Couldn't serialize webhook payload: {}", e);
+            return;
+        }
+    };
+    for attempt in 0..attempts() {
+        if attempt > 0 {
+            tokio::time::sleep(Duration::from_secs(60 << (attempt - 1).min(10))).await;
+        }
+        let (status, error) = match post(&delivery, &body).await {
+            Ok(status) if status.is_success() => (Some(status.as_u16()), None),
+            Ok(status) => (Some(status.as_u16()), Some(status.to_string())),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        delivery.delivered = error.is_none();
+        delivery.attempts.push(Attempt {
+            at: Utc::now(),
+            status,
+            error,
+        });
+        record(&delivery).await;
+        if delivery.delivered {
+            return;
+        }
+    }
+    log::warn!(
+        "Giving up delivering {} of session {} to {}",
+        delivery.payload.event.name(),
+        delivery.payload.session,
+        delivery.url
+    );
+}
+
+/// tell the webhooks, if any, that a session has ended.
+pub fn session_ended(session: &SessionData, event: Event) {
+    let urls = urls();
+    if urls.is_empty() {
+        return;
+    }
+    let transcript = session.transcript().unwrap_or_default();
+    let score = session
+        .resource
+        .as_ref()
+        .and_then(|asset| crate::compare::reference(asset, &session.language))
+        .map(|reference| crate::compare::score(&reference, &transcript));
+    let gaps = session.translations.lock().unwrap().gaps();
+    let payload = Payload {
+        event,
+        session: session.uuid,
+        owner: session.owner,
+        asset: session.resource.clone(),
+        language: session.language.clone(),
+        transcript,
+        score,
+        gaps,
+        created_at: session.created_at,
+        ended_at: Utc::now(),
+    };
+    for url in urls {
+        let delivery = Delivery {
+            id: Uuid::new_v4(),
+            url,
+            payload: payload.clone(),
+            attempts: vec![],
+            delivered: false,
+        };
+        WEBHOOK_RUNTIME.spawn(deliver(delivery));
+    }
+}
+
+/**
+ * the routes for the delivery log, for admins: GET /webhooks/deliveries,
+ * newest first, and POST /webhooks/deliveries/<id>/redeliver to try a
+ * delivery again.
+ */
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("webhooks" / "deliveries"))
+        .and(require_user())
+        .and_then(|user: User| async move {
+            if !user.is_admin() {
+                return Err(warp::reject::custom(Forbidden));
+            }
+            let deliveries: Vec<Delivery> = DELIVERIES
+                .read()
+                .await
+                .deliveries
+                .iter()
+                .rev()
+                .cloned()
+                .collect();
+            Ok(warp::reply::json(&deliveries))
+        });
+
+    let redeliver = warp::post()
+        .and(warp::path!("webhooks" / "deliveries" / Uuid / "redeliver"))
+        .and(require_user())
+        .and_then(|id: Uuid, user: User| async move {
+            if !user.is_admin() {
+                return Err(warp::reject::custom(Forbidden));
+            }
+            let delivery = DELIVERIES
+                .read()
+                .await
+                .deliveries
+                .iter()
+                .find(|x| x.id == id)
+                .cloned()
+                .ok_or(warp::reject::not_found())?;
+            WEBHOOK_RUNTIME.spawn(deliver(delivery));
+            Ok(warp::reply::with_status(
+                warp::reply(),
+                StatusCode::ACCEPTED,
+            ))
+        });
+
+    list.or(redeliver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, block_on};
+
+    #[test]
+    fn payloads_are_signed_with_the_timestamp() {
+        let body = r#"{"event":"session.finished"}"#;
+        assert_eq!(
+            sign("secret", 1700000000, body),
+            "5a64eed95a188475cdcb21dcd9f0c415996f280e4adbb3b134d4ccd29e18e160"
+        );
+        assert_ne!(
+            sign("secret", 1700000001, body),
+            sign("secret", 1700000000, body)
+        );
+    }
+
+    #[test]
+    fn attempts_are_appended_to_the_log() {
+        testing::isolate();
+        let mut delivery = Delivery {
+            id: Uuid::new_v4(),
+            url: "http://localhost/hook".to_string(),
+            payload: Payload {
+                event: Event::Failed,
+                session: Uuid::new_v4(),
+                owner: None,
+                asset: None,
+                language: "de".to_string(),
+                transcript: String::new(),
+                score: None,
+                gaps: vec![],
+                created_at: Utc::now(),
+                ended_at: Utc::now(),
+            },
+            attempts: vec![],
+            delivered: false,
+        };
+        let lines = load_deliveries().lines;
+        for status in [500, 200] {
+            delivery.attempts.push(Attempt {
+                at: Utc::now(),
+                status: Some(status),
+                error: None,
+            });
+            block_on(record(&delivery));
+        }
+        let log = load_deliveries();
+        assert_eq!(log.lines, lines + 2);
+        let logged = log.deliveries.iter().find(|x| x.id == delivery.id);
+        assert_eq!(logged.unwrap().attempts.len(), 2);
+    }
+}
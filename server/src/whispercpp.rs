@@ -1,13 +1,14 @@
 use crate::error::{Er, E};
-use crate::queue;
-use crate::session::process_transcription;
-use crate::translate::{resample, TranslationRequest, TranslationResponse, Translator};
+use crate::translate::{
+    merge_tokens, resample, TranslationRequest, TranslationResponse, Translator, Word,
+};
 use lazy_static::lazy_static;
 use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::OnceLock;
-use thread_priority::set_current_thread_priority;
-use thread_priority::ThreadPriority::Crossplatform;
-use whisper_rs::{FullParams, SamplingStrategy, WhisperContext};
+use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperState};
+
+static MODEL_LOADED: AtomicBool = AtomicBool::new(false);
 
 lazy_static! {
     static ref CTX: OnceLock<WhisperContext> = {
@@ -15,66 +16,72 @@ lazy_static! {
         let ctx = WhisperContext::new(&format!("../models/ggml-{}.bin", model)).unwrap();
         let lock = OnceLock::new();
         lock.set(ctx).unwrap();
+        MODEL_LOADED.store(true, Ordering::Relaxed);
         lock
     };
 }
 
+/// load the model now rather than on the first request.
+pub fn load_model() {
+    lazy_static::initialize(&CTX);
+}
+
+pub fn model_loaded() -> bool {
+    MODEL_LOADED.load(Ordering::Relaxed)
+}
+
 pub struct WhisperCpp {}
 
 impl WhisperCpp {}
 
 impl Translator for WhisperCpp {
-    fn translate(&self, translation_request: TranslationRequest) -> E<()> {
+    fn translate(&self, translation_request: TranslationRequest) -> E<Vec<TranslationResponse>> {
         log::debug!(
             "Sending job {} to translate",
             &translation_request.session_id
         );
-        let session = match crate::session::get_session_sync(&translation_request.session_id) {
-            Some(x) => x,
-            None => {
-                return Err(Er::new(format!(
-                    "Couldn't get session for request {:?}",
-                    translation_request
-                )))
-            }
-        };
 
         let audio_data = translation_request.payload;
 
-        let data = resample(&audio_data, 44100_f64);
+        let data = resample(&audio_data, translation_request.sample_rate as f64);
 
         let mut bytes: Vec<u8> = Vec::with_capacity(4 * data.len());
         for val in &data {
             bytes.extend(&val.to_le_bytes());
         }
 
-        let context = CTX.get().expect("Couldn't get context");
-        let mut state = context.create_state().expect("failed to create state");
+        let context = CTX
+            .get()
+            .ok_or(Er::new("whisper model not loaded".to_string()))?;
+        let mut state = context.create_state()?;
         let mut whisper_params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
         log::debug!("Setting language to {}", translation_request.lang);
         whisper_params.set_language(Some(&translation_request.lang));
-        state
-            .full(whisper_params, &data)
-            .expect("failed to run model");
+        whisper_params.set_token_timestamps(true);
+        state.full(whisper_params, &data)?;
 
-        let num_segments = state
-            .full_n_segments()
-            .expect("failed to get number of segments");
+        let num_segments = state.full_n_segments()?;
         log::debug!("{} segments", num_segments);
+        let mut responses = Vec::with_capacity(num_segments as usize);
         for i in 0..num_segments {
             let segment = match state.full_get_segment_text(i) {
                 Ok(text) => text,
                 Err(_) => "<b>error transcribing</b>".to_string(),
             };
-            let start_timestamp = state
-                .full_get_segment_t0(i)
-                .expect("failed to get start timestamp");
-            let end_timestamp = state
-                .full_get_segment_t1(i)
-                .expect("failed to get end timestamp");
+            // whisper counts in 10ms units, responses are in milliseconds.
+            let start_timestamp = state.full_get_segment_t0(i)? * 10;
+            let end_timestamp = state.full_get_segment_t1(i)? * 10;
 
             log::debug!("[{} - {}]: {}", start_timestamp, end_timestamp, segment);
 
+            let words = match segment_words(&state, i) {
+                Ok(words) => Some(words),
+                Err(e) => {
+                    log::warn!("Couldn't get words for segment {}: {}", i, e);
+                    None
+                }
+            };
+
             let response = TranslationResponse {
                 sequence_number: translation_request.sequence_number,
                 translation: segment,
@@ -82,52 +89,42 @@ impl Translator for WhisperCpp {
                 segment_number: i,
                 segment_start: start_timestamp,
                 segment_end: end_timestamp,
-                uuid: session.uuid.to_string(),
+                uuid: translation_request.uuid.to_string(),
+                words,
             };
+            responses.push(response);
+        }
+        Ok(responses)
+    }
+}
 
-            let result = process_transcription(translation_request.session_id, &response);
-            match result {
-                Ok(_) => (),
-                Err(e) => {
-                    log::warn!("Processing translation failed with error {}", e);
-                    crate::session::mutate_session_sync(
-                        &translation_request.session_id,
-                        |session| session.valid = false,
-                    );
-                }
-            };
+/**
+ * collect the tokens of a segment into words. Special tokens such as
+ * [_BEG_] or <|endoftext|> are dropped, token times are converted from
+ * whisper's 10ms units to milliseconds.
+ */
+fn segment_words(state: &WhisperState, segment: i32) -> E<Vec<Word>> {
+    let num_tokens = state.full_n_tokens(segment)?;
+    let mut tokens = Vec::with_capacity(num_tokens as usize);
+    for token in 0..num_tokens {
+        let text = state.full_get_token_text(segment, token)?;
+        if text.starts_with("[_") || text.starts_with("<|") {
+            continue;
         }
-        Ok(())
+        let data = state.full_get_token_data(segment, token)?;
+        tokens.push((text, data.t0 * 10, data.t1 * 10, data.p));
     }
+    Ok(merge_tokens(&tokens))
 }
 
-pub fn start_translate_pool() -> E<()> {
-    let num_whisper_processes: usize = match env::var("WHISPER_PROCESSES") {
+/// The number of local whisper.cpp workers, from `WHISPER_PROCESSES` or a
+/// quarter of the cpus. Zero disables local transcription.
+pub fn num_processes() -> usize {
+    match env::var("WHISPER_PROCESSES") {
         Ok(num) => num.parse().expect("WHISPER_PROCESSES must be an integer"),
         Err(_) => {
             let num_cpus = num_cpus::get();
             num_cpus / 4
         }
-    };
-    log::debug!("Making thread pool with {} threads.", num_whisper_processes);
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(num_whisper_processes)
-        .build()
-        .expect("Failed to create thread pool");
-    let queue = queue::get_queue();
-
-    for i in 0..num_whisper_processes {
-        log::debug!("Installing {}", i);
-        let mut queue = queue.clone();
-        set_current_thread_priority(Crossplatform(crate::LOWER_PRIORITY.try_into().unwrap()))
-            .unwrap();
-        pool.spawn(move || {
-            queue
-                .subscribe::<WhisperCpp>(&WhisperCpp {})
-                .unwrap_or_else(|_| {
-                    log::warn!("Exiting thread");
-                });
-        });
     }
-    Ok(())
 }
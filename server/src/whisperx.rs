@@ -2,16 +2,50 @@ use log::debug;
 use reqwest::blocking::Client;
 use serde::Deserialize;
 use serde_json::json;
-use warp::ws::Message;
 
-use crate::error::{Er, E};
-use crate::translate::{resample, TranslationRequest, TranslationResponse, Translator};
+use crate::error::E;
+use crate::translate::{
+    check_health, request_timeout, resample, send_cancellable, TranslationRequest,
+    TranslationResponse, Translator, Word,
+};
+
+#[derive(Deserialize, Debug)]
+struct RemoteWhisperWord {
+    word: String,
+    // WhisperX can't align some tokens (e.g. numerals), these come back
+    // without timing or score.
+    start: Option<f32>,
+    end: Option<f32>,
+    score: Option<f32>,
+}
 
 #[derive(Deserialize, Debug)]
 struct RemoteWhisperSegment {
     text: String,
     start: f32,
     end: f32,
+    #[serde(default)]
+    words: Option<Vec<RemoteWhisperWord>>,
+}
+
+impl RemoteWhisperSegment {
+    fn words(&self) -> Option<Vec<Word>> {
+        let words = self.words.as_ref()?;
+        let mut result = Vec::with_capacity(words.len());
+        let mut last_end = self.start;
+        for word in words {
+            let start = word.start.unwrap_or(last_end);
+            let end = word.end.unwrap_or(start);
+            last_end = end;
+            result.push(Word {
+                word: word.word.clone(),
+                start: (start * 1000f32) as i64,
+                end: (end * 1000f32) as i64,
+                confidence: word.score,
+            });
+        }
+        Some(result)
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -23,64 +57,148 @@ struct RemoteWhisperResponse {
 
 pub struct WhisperX {
     client: Client,
+    url: String,
 }
 
 impl WhisperX {
     pub fn new() -> E<Self> {
-        let client = Client::new();
-        Ok(Self { client })
+        Self::with_url(std::env::var("WHISPER_SERVER")?)
+    }
+
+    pub fn with_url(url: String) -> E<Self> {
+        let client = Client::builder().timeout(request_timeout()).build()?;
+        Ok(Self { client, url })
     }
 }
 
 impl Translator for WhisperX {
-    fn translate(&self, translation_request: TranslationRequest) -> E<()> {
+    fn translate(&self, translation_request: TranslationRequest) -> E<Vec<TranslationResponse>> {
         let audio_data = translation_request.payload;
         if audio_data.is_empty() {
-            return Ok(());
+            return Ok(vec![]);
         }
-        let data = resample(&audio_data, 44100_f64);
+        let data = resample(&audio_data, translation_request.sample_rate as f64);
 
-        let url = format!(
-            "{}?lang={}",
-            std::env::var("WHISPER_SERVER").unwrap(),
-            translation_request.lang
-        );
-        let session_id = translation_request.session_id;
-        let session = crate::session::get_session_sync(&translation_request.session_id).ok_or(
-            Er::new(format!("Couldn't get session for request {:?}", session_id)),
-        )?;
+        let url = format!("{}?lang={}", self.url, translation_request.lang);
         debug!("Making request for translation to {}", url);
 
-        let res = self.client.post(url).json(&json!(data)).send()?;
-        let response = res.json::<RemoteWhisperResponse>()?;
+        let res = send_cancellable(
+            self.client.post(url).json(&json!(data)),
+            &translation_request.cancel,
+        )?;
+        let response = res.error_for_status()?.json::<RemoteWhisperResponse>()?;
 
-        for segment in response.segments {
-            let response = TranslationResponse {
+        let num_segments = response.segments.len() as i32;
+        let responses = response
+            .segments
+            .into_iter()
+            .enumerate()
+            .map(|(i, segment)| TranslationResponse {
                 sequence_number: translation_request.sequence_number,
+                words: segment.words(),
                 translation: segment.text,
-                num_segments: 1,
-                segment_number: 0,
+                num_segments,
+                segment_number: i as i32,
                 segment_start: (segment.start * 1000f32) as i64,
                 segment_end: (segment.end * 1000f32) as i64,
-                uuid: session.uuid.to_string(),
-            };
-
-            match session
-                .transcription_sender_tx
-                .as_ref()
-                .expect("Couldn't find sender!")
-                .send(Message::text(json!(response).to_string()))
-            {
-                Ok(_) => (),
-                Err(e) => {
-                    log::warn!("Sending failed with error {}", e);
-                    crate::session::mutate_session_sync(
-                        &translation_request.session_id,
-                        |session| session.valid = false,
-                    );
-                }
-            };
-        }
-        Ok(())
+                uuid: translation_request.uuid.to_string(),
+            })
+            .collect();
+        Ok(responses)
+    }
+
+    fn health_check(&self) -> E<()> {
+        check_health(&self.client, &self.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::TranslationQueue;
+    use crate::session;
+    use crate::testing;
+    use std::collections::HashMap;
+    use warp::Filter;
+
+    /// answers like WhisperX, checking it's sent a second of audio at 16kHz.
+    fn mock_whisperx() -> String {
+        testing::serve(
+            warp::post()
+                .and(warp::query::<HashMap<String, String>>())
+                .and(warp::body::json())
+                .map(|query: HashMap<String, String>, audio: Vec<f32>| {
+                    assert_eq!(query.get("lang").map(|x| x.as_str()), Some("de"));
+                    // a second of audio, give or take the resampler's delay.
+                    assert!((15000..=16000).contains(&audio.len()), "{}", audio.len());
+                    warp::reply::json(&json!({
+                        "language": "de",
+                        "segments": [
+                            {
+                                "text": " Guten Tag",
+                                "start": 0.0,
+                                "end": 0.5,
+                                "words": [
+                                    {"word": "Guten", "start": 0.0, "end": 0.2, "score": 0.9},
+                                    {"word": "Tag"}
+                                ]
+                            },
+                            {"text": " zusammen", "start": 0.5, "end": 1.0}
+                        ]
+                    }))
+                }),
+        )
+    }
+
+    #[test]
+    fn translate_reads_segments_and_words() {
+        let whisperx = WhisperX::with_url(mock_whisperx()).unwrap();
+        let (session_id, _rx) = session::test_session("de");
+        let request = session::get_session_sync(&session_id)
+            .unwrap()
+            .request(0, vec![0.0; 16000]);
+        let responses = whisperx.translate(request).unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[1].segment_number, 1);
+        assert_eq!(
+            (responses[1].segment_start, responses[1].segment_end),
+            (500, 1000)
+        );
+        let words = responses[0].words.as_ref().unwrap();
+        assert_eq!((words[0].start, words[0].end), (0, 200));
+        assert_eq!(words[0].confidence, Some(0.9));
+        // an unaligned word follows the one before it, and has no score.
+        assert_eq!((words[1].start, words[1].end), (200, 200));
+        assert_eq!(words[1].confidence, None);
+        assert!(responses[1].words.is_none());
+    }
+
+    #[test]
+    fn results_go_through_the_session() {
+        let whisperx = WhisperX::with_url(mock_whisperx()).unwrap();
+        let queue = TranslationQueue::new().unwrap();
+        let (session_id, rx) = session::test_session("de");
+        let request = session::get_session_sync(&session_id)
+            .unwrap()
+            .request(0, vec![0.0; 16000]);
+        session::mutate_session_sync(&session_id, |session| {
+            session.sequence_number = 1;
+            session.last_sequence = Some(0);
+        });
+        queue.enqueue(request).unwrap();
+        let mut worker = queue.clone();
+        std::thread::spawn(move || {
+            let _ = worker.subscribe(&whisperx);
+        });
+
+        // the session is finalised once its only sequence is in.
+        let session = testing::wait_for(|| {
+            session::get_session_sync(&session_id).filter(|session| !session.valid)
+        });
+        assert_eq!(session.transcript().unwrap(), " Guten Tag zusammen");
+        assert_eq!(session.get_translation_count().unwrap(), 1);
+        let pushed: Vec<_> = rx.try_iter().collect();
+        assert_eq!(pushed.len(), 2);
+        assert!(pushed[0].to_str().unwrap().contains("Guten Tag"));
     }
 }